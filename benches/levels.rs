@@ -0,0 +1,173 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+// Benchmarks on a synthetic counts file. Run with `cargo bench`.
+
+use std::io::{BufRead, Cursor};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use msite::MSite;
+use statrs::distribution::{ContinuousCDF, Normal};
+
+use mlevels::{levels_from_reader, levels_from_sites, parse_site};
+use mlevels::{LevelsConfig, LevelsCounter};
+
+
+const N_SITES: usize = 200_000;
+
+
+// Deterministic counts lines with mostly low depths, like WGBS
+fn synthetic_counts(n_sites: usize) -> String {
+    const CONTEXTS: [&str; 4] = ["CpG", "CHH", "CXG", "CCG"];
+    let mut state: u64 = 0x2545f4914f6cdd1d;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut counts = String::new();
+    for i in 0..n_sites {
+        let n_reads = next() % 40;
+        let n_meth = if n_reads > 0 { next() % (n_reads + 1) } else { 0 };
+        let meth = if n_reads > 0 {
+            (n_meth as f64)/(n_reads as f64)
+        }
+        else {
+            0.0
+        };
+        let strand = if i % 2 == 0 { '+' } else { '-' };
+        let context = CONTEXTS[(next() % 4) as usize];
+        let pos = i + 1;
+        counts.push_str(
+            &format!("chr1\t{pos}\t{strand}\t{context}\t{meth:.6}\t{n_reads}\n")
+        );
+    }
+    counts
+}
+
+
+// The update as it was before the z-score and calls were cached: the
+// normal quantile is evaluated again for every covered site.
+fn update_per_site_z(lc: &mut LevelsCounter, s: &MSite) {
+    if s.is_mutated() {
+        lc.mutations += 1;
+    }
+    else if s.n_reads > 0 {
+        lc.sites_covered += 1;
+        lc.max_depth = std::cmp::max(lc.max_depth, s.n_reads);
+        lc.total_c += s.n_meth();
+        lc.total_t += s.n_reads - s.n_meth();
+        lc.mean_agg += s.meth;
+
+        let z = Normal::new(0.0, 1.0).unwrap().inverse_cdf(1.0 - 0.05/2.0);
+        let n = s.n_reads as f64;
+        let zz = z*z;
+        let denom = 1.0 + zz/n;
+        let first_term = (s.meth + zz/(2.0*n))/denom;
+        let discriminant = s.meth*(1.0 - s.meth)/n + zz/(4.0*n*n);
+        let second_term = z*discriminant.sqrt()/denom;
+        if first_term - second_term > 0.5 {
+            lc.called_meth += 1;
+        }
+        if first_term + second_term < 0.5 {
+            lc.called_unmeth += 1;
+        }
+    }
+    lc.total_sites += 1;
+}
+
+
+fn bench_update(c: &mut Criterion) {
+    let counts = synthetic_counts(N_SITES);
+    let sites: Vec<MSite> = counts.lines()
+        .map(|line| MSite::build(line).unwrap())
+        .collect();
+
+    let mut group = c.benchmark_group("update");
+    group.throughput(Throughput::Elements(N_SITES as u64));
+    group.bench_function("per_site_z", |b| b.iter(|| {
+        let mut lc = LevelsCounter::default();
+        sites.iter().for_each(|s| update_per_site_z(&mut lc, s));
+        lc
+    }));
+    group.bench_function("cached", |b| b.iter(|| {
+        let mut lc = LevelsCounter::default();
+        sites.iter().for_each(|s| lc.update(s));
+        lc
+    }));
+    group.finish();
+}
+
+
+fn bench_parse(c: &mut Criterion) {
+    let counts = synthetic_counts(N_SITES);
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(counts.len() as u64));
+    group.bench_function("lines_build", |b| b.iter(|| {
+        Cursor::new(counts.as_bytes()).lines()
+            .map(|line| MSite::build(&line.unwrap()).unwrap().n_reads)
+            .sum::<u64>()
+    }));
+    group.bench_function("read_until_borrowed", |b| b.iter(|| {
+        let mut reader = Cursor::new(counts.as_bytes());
+        let mut line = Vec::new();
+        let mut site = MSite::new();
+        let mut n_reads = 0;
+        while reader.read_until(b'\n', &mut line).unwrap() > 0 {
+            parse_site(&line, &mut site).unwrap();
+            n_reads += site.n_reads;
+            line.clear();
+        }
+        n_reads
+    }));
+    group.finish();
+}
+
+
+fn bench_levels_from_reader(c: &mut Criterion) {
+    let counts = synthetic_counts(N_SITES);
+    let config = LevelsConfig::default();
+
+    let mut group = c.benchmark_group("levels_from_reader");
+    group.throughput(Throughput::Bytes(counts.len() as u64));
+    // the loop as it was before lines were parsed into reused buffers:
+    // a String for each line and an MSite owning its chrom and context
+    group.bench_function("lines_build", |b| b.iter(|| {
+        let sites = Cursor::new(counts.as_bytes()).lines()
+            .map(|line| MSite::build(&line.unwrap()).unwrap());
+        levels_from_sites(&config, sites).unwrap()
+    }));
+    group.bench_function("synthetic", |b| b.iter(|| {
+        levels_from_reader(&config, Cursor::new(counts.as_bytes())).unwrap()
+    }));
+    group.finish();
+}
+
+
+criterion_group!(benches, bench_update, bench_parse,
+                 bench_levels_from_reader);
+criterion_main!(benches);
@@ -0,0 +1,223 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+use msite::MSite;
+
+use crate::{ContextParams, LevelsSummary, MlevelsError};
+
+
+/// Levels in fixed-size genomic bins, for each context. Sites must be
+/// sorted; each bin is written once the sites move past it, so only
+/// one bin is held at a time. Bins without sites are not written.
+/// Bins end at the next multiple of the bin size, except that the last
+/// bin of a chromosome ends at its length if the chromosome sizes are
+/// given.
+///
+/// The table has a row for each bin: chrom, start and end, followed
+/// by the sites, sites covered, mean and weighted mean methylation for
+/// each context. The bedGraph has the level of one context in each
+/// bin with covered sites.
+pub struct BinLevels {
+    bin_size: u64,
+    chrom: Vec<u8>,
+    bin: Option<u64>,
+    last_pos: u64,
+    chrom_sizes: HashMap<Vec<u8>, u64>,
+    levels: LevelsSummary,
+    empty: LevelsSummary,
+    table: Option<BufWriter<File>>,
+    bedgraph: Option<BufWriter<File>>,
+    context: usize,
+    unweighted: bool,
+}
+
+
+fn create(
+    path: &Option<String>,
+) -> Result<Option<BufWriter<File>>, MlevelsError> {
+    path.as_deref()
+        .map(|path| File::create(path).map(BufWriter::new))
+        .transpose()
+        .map_err(MlevelsError::Write)
+}
+
+
+// Read the length of each chromosome from a file with the name and
+// length on each line, separated by a tab, like a UCSC chrom.sizes
+// file. Blank lines and lines starting with '#' are ignored.
+fn read_chrom_sizes(
+    path: &str,
+) -> Result<HashMap<Vec<u8>, u64>, MlevelsError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut sizes = HashMap::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let chrom = fields.next().unwrap();
+        let size = fields.next()
+            .and_then(|size| size.trim().parse().ok())
+            .ok_or_else(|| MlevelsError::ChromSizes {
+                path: path.to_string(),
+                line_number: (i + 1) as u64,
+                line: line.to_string(),
+            })?;
+        sizes.insert(chrom.as_bytes().to_vec(), size);
+    }
+    Ok(sizes)
+}
+
+
+impl BinLevels {
+    /// Bins of `bin_size` using copies of `empty` for the counters,
+    /// writing the table and bedGraph if their files are given, and
+    /// with the lengths of chromosomes in `chrom_sizes` if given. The
+    /// bedGraph shows `context`, with the mean methylation if
+    /// `unweighted` and otherwise the weighted mean.
+    pub fn new(
+        bin_size: u64,
+        table: &Option<String>,
+        bedgraph: &Option<String>,
+        chrom_sizes: &Option<String>,
+        context: &str,
+        unweighted: bool,
+        empty: &LevelsSummary,
+    ) -> Result<BinLevels, MlevelsError> {
+        let context = ContextParams::CONTEXTS.iter()
+            .position(|&c| c == context)
+            .expect("context is one of ContextParams::CONTEXTS");
+        let mut bins = BinLevels {
+            bin_size,
+            chrom: Vec::new(),
+            bin: None,
+            last_pos: 0,
+            chrom_sizes: match chrom_sizes {
+                Some(path) => read_chrom_sizes(path)?,
+                None => HashMap::new(),
+            },
+            levels: empty.clone(),
+            empty: empty.clone(),
+            table: create(table)?,
+            bedgraph: create(bedgraph)?,
+            context,
+            unweighted,
+        };
+        if let Some(table) = &mut bins.table {
+            let mut header = String::from("chrom\tstart\tend");
+            for context in ContextParams::CONTEXTS {
+                header.push_str(&format!("\t{context}_sites\t{context}_covered\
+                                          \t{context}_meth\
+                                          \t{context}_meth_weighted"));
+            }
+            writeln!(table, "{header}").map_err(MlevelsError::Write)?;
+        }
+        Ok(bins)
+    }
+
+    /// Count the site in its bin. A completed symmetric CpG is counted
+    /// in the bin of its first position.
+    pub fn update(
+        &mut self,
+        site: &MSite,
+        symmetric: Option<&MSite>,
+    ) -> Result<(), MlevelsError> {
+        // the pair starts at the previous site, so in the current bin
+        if let Some(pair) = symmetric {
+            if self.bin == Some(pair.pos/self.bin_size) &&
+                self.chrom == pair.chrom {
+                self.levels.update_symmetric(pair);
+            }
+        }
+        let bin = site.pos/self.bin_size;
+        if self.bin != Some(bin) || self.chrom != site.chrom {
+            self.write_bin()?;
+            self.chrom.clone_from(&site.chrom);
+            self.bin = Some(bin);
+        }
+        self.last_pos = site.pos;
+        self.levels.update(site);
+        Ok(())
+    }
+
+    // the bin is cut short at the end of the chromosome, if known, but
+    // always holds its sites
+    fn write_bin(&mut self) -> Result<(), MlevelsError> {
+        let bin = match self.bin.take() {
+            Some(bin) => bin,
+            None => return Ok(()),
+        };
+        let mut levels =
+            std::mem::replace(&mut self.levels, self.empty.clone());
+        levels.set_derived_values();
+
+        let chrom = String::from_utf8_lossy(&self.chrom);
+        let start = bin*self.bin_size;
+        let end = match self.chrom_sizes.get(&self.chrom) {
+            Some(&size) => std::cmp::min(start + self.bin_size, size)
+                .max(self.last_pos + 1),
+            None => start + self.bin_size,
+        };
+
+        if let Some(table) = &mut self.table {
+            let mut row = format!("{chrom}\t{start}\t{end}");
+            for (_, lc) in levels.counters() {
+                row.push_str(&format!("\t{}\t{}\t{}\t{}", lc.total_sites,
+                                      lc.sites_covered, lc.mean_meth,
+                                      lc.mean_meth_weighted));
+            }
+            writeln!(table, "{row}").map_err(MlevelsError::Write)?;
+        }
+        if let Some(bedgraph) = &mut self.bedgraph {
+            let lc = levels.counters()[self.context].1;
+            if lc.sites_covered > 0 {
+                let level = if self.unweighted {
+                    lc.mean_meth
+                }
+                else {
+                    lc.mean_meth_weighted
+                };
+                writeln!(bedgraph, "{chrom}\t{start}\t{end}\t{level}")
+                    .map_err(MlevelsError::Write)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the last bin and flush the output
+    pub fn finish(mut self) -> Result<(), MlevelsError> {
+        self.write_bin()?;
+        for out in [&mut self.table, &mut self.bedgraph].into_iter().flatten() {
+            out.flush().map_err(MlevelsError::Write)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use serde::{Serialize, Deserialize};
+
+use crate::{wilson_ci_for_binomial, z_score, ContextParams, LevelsCounter};
+use crate::LevelsSummary;
+
+
+/// The fraction of reads showing conversion at covered sites of some
+/// contexts, with a confidence interval
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+pub struct ConversionEstimate {
+    /// The interval is at confidence 1 - alpha
+    #[serde(default)]
+    pub alpha: f64,
+    pub sites_covered: u64,
+    pub coverage: u64,
+    pub rate: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+
+impl ConversionEstimate {
+    // none without coverage, where the rate is not defined
+    fn new(
+        alpha: f64,
+        counters: &[&LevelsCounter],
+    ) -> Option<ConversionEstimate> {
+        let sites_covered = counters.iter().map(|lc| lc.sites_covered).sum();
+        let total_t: u64 = counters.iter().map(|lc| lc.total_t).sum();
+        let coverage: u64 =
+            counters.iter().map(|lc| lc.get_coverage()).sum();
+        if coverage == 0 {
+            return None;
+        }
+        // the same as 1 - weighted methylation
+        let rate = (total_t as f64)/(coverage as f64);
+        let (mut lower, mut upper) = (0.0, 0.0);
+        wilson_ci_for_binomial(z_score(alpha), coverage, rate,
+                               &mut lower, &mut upper);
+        Some(ConversionEstimate {
+            alpha,
+            sites_covered,
+            coverage,
+            rate,
+            lower,
+            upper,
+        })
+    }
+}
+
+
+/// Bisulfite conversion rates estimated from contexts other than CpG,
+/// where methylation is expected to be rare, e.g., in mammals. Each is
+/// only estimated if its sites have coverage.
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+pub struct ConversionRates {
+    /// At the alpha for calling CHH sites
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chh: Option<ConversionEstimate>,
+    /// CHH, CCG and CXG sites together, at the smallest of their alphas
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub non_cpg: Option<ConversionEstimate>,
+}
+
+
+impl ConversionRates {
+    /// The rates for the levels in `summary`, computed with `params`,
+    /// or none if no sites other than CpGs have coverage
+    pub fn new(
+        summary: &LevelsSummary,
+        params: &ContextParams,
+    ) -> Option<ConversionRates> {
+        let non_cpg_alpha = params.chh.alpha
+            .min(params.ccg.alpha)
+            .min(params.cxg.alpha);
+        let rates = ConversionRates {
+            chh: ConversionEstimate::new(params.chh.alpha, &[&summary.chh]),
+            non_cpg: ConversionEstimate::new(
+                non_cpg_alpha,
+                &[&summary.chh, &summary.ccg, &summary.cxg],
+            ),
+        };
+        rates.non_cpg.is_some().then_some(rates)
+    }
+}
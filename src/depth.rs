@@ -0,0 +1,183 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use serde::{Serialize, Deserialize};
+
+
+/// Quantiles of the depth of sites, from a histogram, so depths at or
+/// above the cap are reported as the cap
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+pub struct DepthQuantiles {
+    pub q10: u64,
+    pub q25: u64,
+    pub q75: u64,
+    pub q90: u64,
+}
+
+
+/// Fractions of sites with at least some depth; those above the cap of
+/// the histogram are not known
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+pub struct DepthFractions {
+    #[serde(rename = "1x")]
+    pub x1: Option<f64>,
+    #[serde(rename = "5x")]
+    pub x5: Option<f64>,
+    #[serde(rename = "10x")]
+    pub x10: Option<f64>,
+    #[serde(rename = "30x")]
+    pub x30: Option<f64>,
+}
+
+
+/// The number of sites with each depth, with all sites at or above a
+/// cap counted together in the last bucket
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+#[serde(try_from = "UncheckedDepthHistogram")]
+pub struct DepthHistogram {
+    pub cap: u64,
+    /// Sites with each depth from 0 to the cap
+    pub counts: Vec<u64>,
+
+    // derived values
+    #[serde(default)]
+    pub median: u64,
+    #[serde(default)]
+    pub quantiles: DepthQuantiles,
+    #[serde(default)]
+    pub fraction_at_least: DepthFractions,
+}
+
+
+// A histogram as read back from output, before checking that it has a
+// count for each depth up to the cap
+#[derive(Deserialize)]
+struct UncheckedDepthHistogram {
+    cap: u64,
+    counts: Vec<u64>,
+    #[serde(default)]
+    median: u64,
+    #[serde(default)]
+    quantiles: DepthQuantiles,
+    #[serde(default)]
+    fraction_at_least: DepthFractions,
+}
+
+
+impl TryFrom<UncheckedDepthHistogram> for DepthHistogram {
+    type Error = String;
+    fn try_from(
+        hist: UncheckedDepthHistogram,
+    ) -> Result<DepthHistogram, String> {
+        if hist.cap.checked_add(1) != Some(hist.counts.len() as u64) {
+            return Err(format!("depth histogram with cap {} has {} counts",
+                               hist.cap, hist.counts.len()));
+        }
+        Ok(DepthHistogram {
+            cap: hist.cap,
+            counts: hist.counts,
+            median: hist.median,
+            quantiles: hist.quantiles,
+            fraction_at_least: hist.fraction_at_least,
+        })
+    }
+}
+
+
+impl DepthHistogram {
+    pub fn new(cap: u64) -> DepthHistogram {
+        DepthHistogram {
+            cap,
+            counts: vec![0; cap as usize + 1],
+            median: 0,
+            quantiles: Default::default(),
+            fraction_at_least: Default::default(),
+        }
+    }
+
+    pub fn add(&mut self, depth: u64) {
+        self.counts[std::cmp::min(depth, self.cap) as usize] += 1;
+    }
+
+    /// Add the counts from another histogram; with different caps the
+    /// smaller one is kept. The derived values must be set again after.
+    pub fn merge(&mut self, other: &DepthHistogram) {
+        if other.cap < self.cap {
+            let beyond: u64 = self.counts[other.cap as usize..].iter().sum();
+            self.counts.truncate(other.cap as usize);
+            self.counts.push(beyond);
+            self.cap = other.cap;
+        }
+        let last = self.cap as usize;
+        for (depth, count) in other.counts.iter().enumerate() {
+            self.counts[std::cmp::min(depth, last)] += count;
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The smallest depth with at least a fraction `q` of the sites at
+    /// that depth or below
+    pub fn quantile(&self, q: f64) -> u64 {
+        let total = self.total();
+        let rank = std::cmp::max((q*total as f64).ceil() as u64, 1);
+        let mut cumulative = 0;
+        for (depth, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                return depth as u64;
+            }
+        }
+        0
+    }
+
+    /// Fraction of the sites with at least this depth, if the cap is
+    /// not below it
+    pub fn fraction_at_least(&self, depth: u64) -> Option<f64> {
+        if depth > self.cap {
+            return None;
+        }
+        let at_least: u64 = self.counts[depth as usize..].iter().sum();
+        Some((at_least as f64)/(self.total() as f64))
+    }
+
+    pub fn set_derived_values(&mut self) {
+        self.median = self.quantile(0.5);
+        self.quantiles = DepthQuantiles {
+            q10: self.quantile(0.1),
+            q25: self.quantile(0.25),
+            q75: self.quantile(0.75),
+            q90: self.quantile(0.9),
+        };
+        self.fraction_at_least = DepthFractions {
+            x1: self.fraction_at_least(1),
+            x5: self.fraction_at_least(5),
+            x10: self.fraction_at_least(10),
+            x30: self.fraction_at_least(30),
+        };
+    }
+}
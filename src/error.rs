@@ -0,0 +1,127 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::fmt;
+use std::io;
+
+
+/// Everything that can go wrong while computing levels. The library
+/// never exits the process; callers decide what to do with these.
+#[derive(Debug)]
+pub enum MlevelsError {
+    /// Failure opening or reading the input
+    Io(io::Error),
+    /// A line of the counts file could not be parsed as a site
+    Parse { line_number: u64, line: String },
+    /// A site whose context is not one of CpG, CHH, CCG or CXG
+    UnknownContext { line_number: u64, line: String },
+    /// A site that is not in sorted order, with what is wrong
+    Unsorted { line_number: u64, site: String, problem: String },
+    /// A site given already parsed that could not be counted, by its
+    /// number in the sequence of sites, with what is wrong
+    Site { site_number: u64, site: String, problem: String },
+    /// A line of a BED file that could not be used
+    Bed { path: String, line_number: u64, message: String },
+    /// A line of a chromosome sizes file without a name and length
+    ChromSizes { path: String, line_number: u64, line: String },
+    /// An mlevels output that could not be read back or combined
+    Report { path: String, message: String },
+    /// Failure while processing one of several samples
+    Sample { sample: String, source: Box<MlevelsError> },
+    /// Failure creating or writing the output
+    Write(io::Error),
+}
+
+
+impl MlevelsError {
+    /// Exit status for the command line tool
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            MlevelsError::Io(_) => 2,
+            MlevelsError::Parse { .. } => 3,
+            MlevelsError::UnknownContext { .. } => 3,
+            MlevelsError::Unsorted { .. } => 3,
+            MlevelsError::Site { .. } => 3,
+            MlevelsError::Bed { .. } => 3,
+            MlevelsError::ChromSizes { .. } => 3,
+            MlevelsError::Report { .. } => 3,
+            MlevelsError::Sample { source, .. } => source.exit_code(),
+            MlevelsError::Write(_) => 4,
+        }
+    }
+}
+
+
+impl fmt::Display for MlevelsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MlevelsError::Io(err) => write!(f, "input error: {err}"),
+            MlevelsError::Parse { line_number, line } => {
+                write!(f, "failed parsing site (line {line_number}): {line}")
+            }
+            MlevelsError::UnknownContext { line_number, line } => {
+                write!(f, "bad site type (line {line_number}): {line}")
+            }
+            MlevelsError::Unsorted { line_number, site, problem } => {
+                write!(f, "{problem} (line {line_number}): {site}")
+            }
+            MlevelsError::Site { site_number, site, problem } => {
+                write!(f, "{problem} (site {site_number}): {site}")
+            }
+            MlevelsError::Bed { path, line_number, message } => {
+                write!(f, "bad BED file {path} (line {line_number}): {message}")
+            }
+            MlevelsError::ChromSizes { path, line_number, line } => {
+                write!(f, "bad chromosome sizes file {path} \
+                           (line {line_number}): {line}")
+            }
+            MlevelsError::Report { path, message } => {
+                write!(f, "bad mlevels output {path}: {message}")
+            }
+            MlevelsError::Sample { sample, source } => {
+                write!(f, "sample {sample}: {source}")
+            }
+            MlevelsError::Write(err) => write!(f, "output error: {err}"),
+        }
+    }
+}
+
+
+impl std::error::Error for MlevelsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MlevelsError::Io(err) | MlevelsError::Write(err) => Some(err),
+            MlevelsError::Sample { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+
+impl From<io::Error> for MlevelsError {
+    fn from(err: io::Error) -> Self {
+        MlevelsError::Io(err)
+    }
+}
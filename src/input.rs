@@ -0,0 +1,404 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, Cursor};
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use flate2::Crc;
+use memmap2::Mmap;
+
+use crate::MlevelsError;
+
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+
+// gzip header up to and including the BGZF "BC" subfield identifier
+const BGZF_HEADER_SIZE: usize = 18;
+const GZIP_FEXTRA: u8 = 0x04;
+
+// number of BGZF blocks each thread inflates per batch
+const BLOCKS_PER_THREAD: usize = 16;
+
+
+#[derive(Debug, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Bgzf,
+}
+
+
+fn detect_compression(head: &[u8]) -> Compression {
+    if head.len() < GZIP_MAGIC.len() || head[..3] != GZIP_MAGIC {
+        return Compression::None;
+    }
+    // BGZF is gzip with an extra field holding the "BC" subfield
+    if head.len() >= BGZF_HEADER_SIZE && head[3] & GZIP_FEXTRA != 0 &&
+        head[12] == b'B' && head[13] == b'C' {
+        return Compression::Bgzf;
+    }
+    Compression::Gzip
+}
+
+
+/// Wrap a reader of counts data so that gzip or BGZF compressed input
+/// is decompressed on the fly. The format is detected from the magic
+/// bytes, and with more than one thread the blocks of BGZF input are
+/// inflated in parallel.
+pub fn decompressing_reader<R: Read + 'static>(
+    mut reader: R,
+    threads: usize,
+) -> Result<Box<dyn BufRead>, MlevelsError> {
+
+    // peek at the start of the input, then put it back in front
+    let mut head = Vec::with_capacity(BGZF_HEADER_SIZE);
+    (&mut reader).take(BGZF_HEADER_SIZE as u64).read_to_end(&mut head)?;
+    let compression = detect_compression(&head);
+    let reader = Cursor::new(head).chain(reader);
+
+    Ok(match compression {
+        Compression::None => Box::new(BufReader::new(reader)),
+        Compression::Bgzf if threads > 1 => {
+            Box::new(BgzfReader::new(reader, threads))
+        }
+        // MultiGzDecoder also handles BGZF, which is multi-member gzip
+        _ => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+    })
+}
+
+
+/// Open a counts file as stored, without decompressing it. The name
+/// "-" means standard input.
+pub fn raw_input(input: &str) -> Result<Box<dyn Read>, MlevelsError> {
+    if input == "-" {
+        Ok(Box::new(io::stdin()))
+    }
+    else {
+        Ok(Box::new(File::open(input)?))
+    }
+}
+
+
+/// Open a counts file that may be plain text, gzip or BGZF. The name
+/// "-" means standard input.
+pub fn open_counts(
+    input: &str,
+    threads: usize,
+) -> Result<Box<dyn BufRead>, MlevelsError> {
+    decompressing_reader(raw_input(input)?, threads)
+}
+
+
+/// Memory map the counts file `input` so its lines can be scanned in
+/// place. Returns `None` for standard input, pipes and other special
+/// files, empty files and compressed files, which must be read with
+/// `open_counts`, and also if the file cannot be mapped. The file must
+/// not be changed while the map is in use.
+pub fn map_counts(input: &str) -> Result<Option<Mmap>, MlevelsError> {
+    if input == "-" || !std::fs::metadata(input)?.is_file() {
+        return Ok(None);
+    }
+    let file = File::open(input)?;
+    let mut head = Vec::with_capacity(GZIP_MAGIC.len());
+    (&file).take(GZIP_MAGIC.len() as u64).read_to_end(&mut head)?;
+    if head.is_empty() || detect_compression(&head) != Compression::None {
+        return Ok(None);
+    }
+    // SAFETY: the map is only read, and the file is not expected to be
+    // truncated while counting
+    Ok(unsafe { Mmap::map(&file) }.ok())
+}
+
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("BGZF: {msg}"))
+}
+
+
+// Reads the next BGZF block whole, returning None at the end of the
+// input.
+fn read_bgzf_block<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 12];
+    let mut n_read = 0;
+    while n_read < header.len() {
+        match reader.read(&mut header[n_read..]) {
+            Ok(0) if n_read == 0 => return Ok(None),
+            Ok(0) => return Err(invalid_data("truncated block header")),
+            Ok(n) => n_read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if header[..3] != GZIP_MAGIC || header[3] & GZIP_FEXTRA == 0 {
+        return Err(invalid_data("bad block header"));
+    }
+
+    let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let mut extra = vec![0u8; xlen];
+    reader.read_exact(&mut extra)?;
+
+    // find the BC subfield giving the total block size minus one
+    let mut bsize = None;
+    let mut i = 0;
+    while i + 4 <= xlen {
+        let slen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+        if extra[i] == b'B' && extra[i + 1] == b'C' && slen == 2 &&
+            i + 6 <= xlen {
+            bsize = Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]));
+        }
+        i += 4 + slen;
+    }
+    let bsize = bsize.ok_or_else(|| invalid_data("missing BC subfield"))?;
+
+    // the remainder is the deflate data followed by CRC32 and ISIZE
+    let remaining = (bsize as usize + 1)
+        .checked_sub(header.len() + xlen)
+        .filter(|&r| r >= 8)
+        .ok_or_else(|| invalid_data("bad block size"))?;
+    let mut block = vec![0u8; remaining];
+    reader.read_exact(&mut block)?;
+    Ok(Some(block))
+}
+
+
+// Inflates the deflate data and trailer of one BGZF block
+fn inflate_bgzf_block(block: &[u8]) -> io::Result<Vec<u8>> {
+    let (cdata, trailer) = block.split_at(block.len() - 8);
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let isize = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+
+    let mut out = Vec::with_capacity(isize as usize);
+    DeflateDecoder::new(cdata).read_to_end(&mut out)?;
+
+    let mut check = Crc::new();
+    check.update(&out);
+    if out.len() != isize as usize || check.sum() != crc {
+        return Err(invalid_data("block failed integrity check"));
+    }
+    Ok(out)
+}
+
+
+/// Reader for BGZF input that inflates batches of blocks on several
+/// threads, handing back the decompressed bytes in their original
+/// order.
+pub struct BgzfReader<R: Read> {
+    inner: R,
+    threads: usize,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+
+impl<R: Read> BgzfReader<R> {
+    pub fn new(inner: R, threads: usize) -> BgzfReader<R> {
+        BgzfReader {
+            inner,
+            threads: std::cmp::max(threads, 1),
+            buf: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    // read a batch of blocks and inflate them into the buffer
+    fn next_batch(&mut self) -> io::Result<()> {
+        let mut blocks = Vec::new();
+        while blocks.len() < self.threads * BLOCKS_PER_THREAD {
+            match read_bgzf_block(&mut self.inner)? {
+                Some(block) => blocks.push(block),
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+        self.buf.clear();
+        self.pos = 0;
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let per_thread = blocks.len().div_ceil(self.threads);
+        let inflated: Vec<io::Result<Vec<Vec<u8>>>> =
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = blocks
+                    .chunks(per_thread)
+                    .map(|chunk| scope.spawn(move || {
+                        chunk.iter()
+                            .map(|block| inflate_bgzf_block(block))
+                            .collect::<io::Result<Vec<_>>>()
+                    }))
+                    .collect();
+                handles.into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect()
+            });
+
+        for chunk in inflated {
+            for block in chunk? {
+                self.buf.extend_from_slice(&block);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = std::cmp::min(available.len(), out.len());
+        out[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+
+impl<R: Read> BufRead for BgzfReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // empty blocks (e.g., the EOF marker) give empty batches
+        while self.pos >= self.buf.len() && !self.done {
+            self.next_batch()?;
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.buf.len());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression as Level;
+
+    // the empty block that ends a BGZF file
+    const BGZF_EOF: [u8; 28] = [
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06,
+        0x00, 0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(data).unwrap();
+        let cdata = encoder.finish().unwrap();
+        let mut crc = Crc::new();
+        crc.update(data);
+
+        // header and extra field, deflate data, then CRC32 and ISIZE
+        let bsize = (12 + 6 + cdata.len() + 8 - 1) as u16;
+        let mut block = vec![0x1f, 0x8b, 0x08, GZIP_FEXTRA,
+                             0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0];
+        block.extend_from_slice(&bsize.to_le_bytes());
+        block.extend_from_slice(&cdata);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    fn counts_text() -> Vec<u8> {
+        (0..20000)
+            .map(|i| format!("chr1\t{i}\t+\tCpG\t0.{}\t{}\n", i % 10, i % 40))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    // many small blocks, so several batches are needed, with an empty
+    // block in the middle and the EOF marker at the end
+    fn bgzf(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        for (i, chunk) in data.chunks(1000).enumerate() {
+            compressed.extend(bgzf_block(chunk));
+            if i == 100 {
+                compressed.extend(bgzf_block(&[]));
+            }
+        }
+        compressed.extend_from_slice(&BGZF_EOF);
+        compressed
+    }
+
+    fn read_all(reader: &mut dyn Read) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn bgzf_round_trip() {
+        let data = counts_text();
+        let compressed = bgzf(&data);
+        assert_eq!(detect_compression(&compressed), Compression::Bgzf);
+
+        let mut expected = Vec::new();
+        MultiGzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut expected).unwrap();
+        assert_eq!(expected, data);
+
+        for threads in [1, 2, 4] {
+            let mut reader =
+                decompressing_reader(Cursor::new(compressed.clone()), threads)
+                    .unwrap();
+            assert_eq!(read_all(&mut reader).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn bgzf_eof_marker_only() {
+        let mut reader = BgzfReader::new(BGZF_EOF.as_slice(), 4);
+        assert!(read_all(&mut reader).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bgzf_truncated() {
+        let compressed = bgzf(&counts_text());
+        let first_block = bgzf_block(&counts_text()[..1000]).len();
+        // inside the deflate data, then inside a block header
+        for cut in [compressed.len()/2, first_block + 5] {
+            let truncated = compressed[..cut].to_vec();
+            let mut reader =
+                decompressing_reader(Cursor::new(truncated), 4).unwrap();
+            assert!(read_all(&mut reader).is_err());
+        }
+    }
+
+    #[test]
+    fn bgzf_corrupt_block() {
+        let mut compressed = bgzf(&counts_text());
+        // the CRC32 of the first block
+        let first_block = bgzf_block(&counts_text()[..1000]).len();
+        compressed[first_block - 8] ^= 0xff;
+        let mut reader = BgzfReader::new(compressed.as_slice(), 2);
+        let err = read_all(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -23,11 +23,70 @@
  * SOFTWARE.
  */
 
+mod conversion;
+pub use conversion::{ConversionEstimate, ConversionRates};
 
+mod depth;
+pub use depth::{DepthFractions, DepthHistogram, DepthQuantiles};
+
+mod error;
+pub use error::MlevelsError;
+
+mod input;
+pub use input::{decompressing_reader, map_counts, open_counts, raw_input};
+pub use input::BgzfReader;
+
+mod parallel;
+pub use parallel::report_from_chunks;
+
+mod order;
+pub use order::{SortCheck, SortProblems};
+use order::SortChecker;
+
+mod parse;
+pub use parse::{parse_site, LineTally, ParseError, SkippedLines};
+
+mod regions;
+pub use regions::{BedReader, BedRegions, GenomicRegion, SiteFilter};
+
+mod bins;
+pub use bins::BinLevels;
+
+mod merge;
+pub use merge::{read_report, run_merge};
+
+mod meth;
+pub use meth::{MethBands, MethHistogram};
+
+mod metadata;
+pub use metadata::{sha256_hex, FilterMetadata, HashDigest, HashingReader};
+pub use metadata::{InputMetadata, RunMetadata, RunTimer};
+
+mod multiqc;
+pub use multiqc::{sample_name_for, write_multiqc};
+
+mod output;
+pub use output::{JsonWriter, OutputFormat, ReportWriter, TomlWriter};
+pub use output::{TsvWriter, YamlWriter};
+
+mod roi;
+pub use roi::RegionLevels;
+
+mod samples;
+pub use samples::{levels_for_samples, read_sample_sheet, run_samples};
+pub use samples::{write_sample_table, Sample};
+
+mod spread;
+use spread::{MethSketch, MethSpread};
+
+mod sum;
+
+mod table;
+pub use table::{write_chrom_table, COUNTER_COLUMNS};
 
-use std::process;
 use std::fs::File;
-use std::io::{prelude::*,BufReader};
+use std::io::prelude::*;
+use std::sync::{Arc, OnceLock};
 
 use serde::{Serialize, Deserialize};
 use msite::MSite;
@@ -36,17 +95,20 @@ use statrs::distribution::Normal;
 use statrs::distribution::ContinuousCDF;
 
 
+fn z_score(alpha: f64) -> f64 {
+    let g = Normal::new(0.0, 1.0).unwrap();
+    g.inverse_cdf(1.0 - alpha/2.0)
+}
+
+
 // ADS: (TODO) test this!
 fn wilson_ci_for_binomial(
-    alpha: f64,
+    z: f64,
     n: u64,
     p_hat: f64,
     lower: &mut f64,
     upper: &mut f64,
 ) {
-    let g = Normal::new(0.0, 1.0).unwrap();
-    let z: f64 = g.inverse_cdf(1.0 - alpha/2.0);
-
     let n = n as f64;
     let zz = z*z;
     let denom: f64 = 1.0 + zz/n;
@@ -64,7 +126,158 @@ fn wilson_ci_for_binomial(
     }
 }
 
-#[derive(Default,Serialize,Deserialize)]
+/// Parameters for calling a site methylated or unmethylated: a site
+/// is called when the confidence interval for its level, at
+/// confidence 1 - alpha, lies entirely above or below the threshold.
+/// Only sites with a depth from min_depth to max_depth count as
+/// covered; sites with reads outside that range are excluded.
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
+pub struct CallParams {
+    pub alpha: f64,
+    pub threshold: f64,
+    #[serde(default = "CallParams::default_min_depth")]
+    pub min_depth: u64,
+    #[serde(default)]
+    pub max_depth: Option<u64>,
+}
+
+
+impl CallParams {
+    fn default_min_depth() -> u64 {
+        1
+    }
+}
+
+
+impl Default for CallParams {
+    fn default() -> CallParams {
+        CallParams {
+            alpha: 0.05,
+            threshold: 0.5,
+            min_depth: CallParams::default_min_depth(),
+            max_depth: None,
+        }
+    }
+}
+
+
+/// The calling parameters for each cytosine context
+#[derive(Debug,Default,Clone,Copy,PartialEq,Serialize,Deserialize)]
+pub struct ContextParams {
+    pub cytosine: CallParams,
+    pub cpg: CallParams,
+    pub cpg_symmetric: CallParams,
+    pub chh: CallParams,
+    pub ccg: CallParams,
+    pub cxg: CallParams,
+}
+
+
+impl ContextParams {
+    /// Names of the contexts, as they appear in the output
+    pub const CONTEXTS: [&'static str; 6] =
+        ["cytosine", "cpg", "cpg_symmetric", "chh", "ccg", "cxg"];
+
+    /// The same parameters for every context
+    pub fn uniform(params: CallParams) -> ContextParams {
+        ContextParams {
+            cytosine: params,
+            cpg: params,
+            cpg_symmetric: params,
+            chh: params,
+            ccg: params,
+            cxg: params,
+        }
+    }
+
+    /// The parameters for a context by name
+    pub fn get_mut(&mut self, context: &str) -> Option<&mut CallParams> {
+        match context {
+            "cytosine" => Some(&mut self.cytosine),
+            "cpg" => Some(&mut self.cpg),
+            "cpg_symmetric" => Some(&mut self.cpg_symmetric),
+            "chh" => Some(&mut self.chh),
+            "ccg" => Some(&mut self.ccg),
+            "cxg" => Some(&mut self.cxg),
+            _ => None,
+        }
+    }
+}
+
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Call {
+    Meth,
+    Unmeth,
+    Neither,
+}
+
+
+// Calls sites methylated or unmethylated. The z-score is computed
+// once for the alpha, and calls for depths up to MAX_TABLE_DEPTH are
+// tabulated by (n_reads, n_meth), using n_meth/n_reads as the level.
+// The table is only used for sites with exactly that level, as levels
+// written with fewer digits can be called differently near the
+// threshold. Copies of a caller share the table.
+#[derive(Clone)]
+struct MethCaller {
+    params: CallParams,
+    z: f64,
+    table: Arc<Vec<Call>>,
+}
+
+
+impl MethCaller {
+    const MAX_TABLE_DEPTH: u64 = 128;
+
+    fn new(params: CallParams) -> MethCaller {
+        let z = z_score(params.alpha);
+        let mut caller = MethCaller { params, z, table: Default::default() };
+        caller.table = Arc::new((0..=MethCaller::MAX_TABLE_DEPTH)
+            .flat_map(|n| (0..=n).map(move |k| (n, k)))
+            .map(|(n, k)| caller.compute_call(n, (k as f64)/(n as f64)))
+            .collect());
+        caller
+    }
+
+    fn compute_call(&self, n_reads: u64, meth: f64) -> Call {
+        let mut lower: f64 = 0.0;
+        let mut upper: f64 = 0.0;
+        wilson_ci_for_binomial(self.z, n_reads, meth, &mut lower, &mut upper);
+        if lower > self.params.threshold {
+            Call::Meth
+        }
+        else if upper < self.params.threshold {
+            Call::Unmeth
+        }
+        else {
+            Call::Neither
+        }
+    }
+
+    fn call(&self, n_reads: u64, n_meth: u64, meth: f64) -> Call {
+        if n_reads <= MethCaller::MAX_TABLE_DEPTH && n_meth <= n_reads &&
+            (n_meth as f64)/(n_reads as f64) == meth {
+            self.table[(n_reads*(n_reads + 1)/2 + n_meth) as usize]
+        }
+        else {
+            self.compute_call(n_reads, meth)
+        }
+    }
+}
+
+
+// built once, as a caller is made for every counter read back from
+// output
+impl Default for MethCaller {
+    fn default() -> MethCaller {
+        static DEFAULT: OnceLock<MethCaller> = OnceLock::new();
+        DEFAULT.get_or_init(|| MethCaller::new(Default::default())).clone()
+    }
+}
+
+
+#[derive(Default,Clone,Serialize,Deserialize)]
 pub struct LevelsCounter {
     pub total_sites: u64,
     pub sites_covered: u64,
@@ -72,6 +285,12 @@ pub struct LevelsCounter {
     pub total_t: u64,
     pub max_depth: u64,
     pub mutations: u64,
+    /// Sites with reads, but fewer than the minimum depth
+    #[serde(default)]
+    pub excluded_low_depth: u64,
+    /// Sites with more reads than the maximum depth
+    #[serde(default)]
+    pub excluded_high_depth: u64,
     pub called_meth: u64,
     pub called_unmeth: u64,
     pub mean_agg: f64,
@@ -84,33 +303,179 @@ pub struct LevelsCounter {
     pub mean_meth: f64,
     pub mean_meth_weighted: f64,
     pub fractional_meth: f64,
+    /// Sample variance and standard deviation of the levels of covered
+    /// sites, NaN if not known
+    #[serde(default = "not_known")]
+    pub meth_variance: f64,
+    #[serde(default = "not_known")]
+    pub meth_sd: f64,
+    /// Approximate median and quartiles of the levels of covered sites,
+    /// NaN if not known
+    #[serde(default = "not_known")]
+    pub meth_median: f64,
+    #[serde(default = "not_known")]
+    pub meth_q25: f64,
+    #[serde(default = "not_known")]
+    pub meth_q75: f64,
+
+    /// Depths of all sites, if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depth_histogram: Option<DepthHistogram>,
+    /// Methylation levels of covered sites, if requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meth_histogram: Option<MethHistogram>,
+    /// Levels of covered sites in fine bins, for the quantiles; only
+    /// written if requested, so they can be found again when outputs
+    /// are merged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meth_sketch: Option<MethSketch>,
+
+    #[serde(skip)]
+    caller: MethCaller,
+    // sums for the variance, if it is computed
+    #[serde(skip)]
+    meth_spread: Option<MethSpread>,
+}
+
+
+// the spread of levels for counters without it, e.g., read back from
+// output that does not have it
+fn not_known() -> f64 {
+    f64::NAN
 }
 
 
 
 impl LevelsCounter {
+    pub fn new(params: CallParams) -> LevelsCounter {
+        LevelsCounter::with_caller(MethCaller::new(params))
+    }
+    fn with_caller(caller: MethCaller) -> LevelsCounter {
+        LevelsCounter {
+            caller,
+            meth_variance: f64::NAN,
+            meth_sd: f64::NAN,
+            meth_median: f64::NAN,
+            meth_q25: f64::NAN,
+            meth_q75: f64::NAN,
+            ..Default::default()
+        }
+    }
+    pub fn params(&self) -> CallParams {
+        self.caller.params
+    }
+    /// Also find the variance and quantiles of the levels of covered
+    /// sites
+    pub fn with_spread(mut self) -> LevelsCounter {
+        self.meth_spread = Some(MethSpread::default());
+        self.meth_sketch = Some(MethSketch::default());
+        self
+    }
+    /// Also count the sites with each depth, up to `cap`
+    pub fn with_depth_histogram(mut self, cap: u64) -> LevelsCounter {
+        self.depth_histogram = Some(DepthHistogram::new(cap));
+        self
+    }
+    /// Also count the covered sites with at least `min_depth` reads in
+    /// `n_bins` bins of methylation level
+    pub fn with_meth_histogram(
+        mut self,
+        n_bins: usize,
+        min_depth: u64,
+    ) -> LevelsCounter {
+        self.meth_histogram = Some(MethHistogram::new(n_bins, min_depth));
+        self
+    }
+    // counters read back from output only have the variance as a
+    // derived value
+    fn spread(&self) -> Option<MethSpread> {
+        match &self.meth_spread {
+            Some(spread) => Some(spread.clone()),
+            None => MethSpread::from_summary(self.sites_covered,
+                                             self.mean_meth,
+                                             self.meth_variance),
+        }
+    }
+    /// Add the counts from another counter, e.g., for a different
+    /// part of the genome. The derived values must be set again after.
+    pub fn merge(&mut self, other: &LevelsCounter) {
+        self.total_sites += other.total_sites;
+        self.sites_covered += other.sites_covered;
+        self.total_c += other.total_c;
+        self.total_t += other.total_t;
+        self.max_depth = std::cmp::max(self.max_depth, other.max_depth);
+        self.mutations += other.mutations;
+        self.excluded_low_depth += other.excluded_low_depth;
+        self.excluded_high_depth += other.excluded_high_depth;
+        self.called_meth += other.called_meth;
+        self.called_unmeth += other.called_unmeth;
+        self.mean_agg += other.mean_agg;
+        // the spread is only known if it is for all sites
+        self.meth_spread = match (self.spread(), other.spread()) {
+            (Some(mut spread), Some(other)) => {
+                spread.merge(&other);
+                Some(spread)
+            }
+            _ => {
+                self.meth_variance = f64::NAN;
+                None
+            }
+        };
+        match (&mut self.meth_sketch, &other.meth_sketch) {
+            (Some(sketch), Some(other)) => sketch.merge(other),
+            _ => {
+                self.meth_sketch = None;
+                self.meth_median = f64::NAN;
+                self.meth_q25 = f64::NAN;
+                self.meth_q75 = f64::NAN;
+            }
+        }
+        // a histogram is only kept if it has all sites
+        match (&mut self.depth_histogram, &other.depth_histogram) {
+            (Some(hist), Some(other)) => hist.merge(other),
+            _ => self.depth_histogram = None,
+        }
+        match (&mut self.meth_histogram, &other.meth_histogram) {
+            (Some(hist), Some(other)) if hist.compatible(other) => {
+                hist.merge(other)
+            }
+            _ => self.meth_histogram = None,
+        }
+    }
     pub fn update(&mut self, s: &MSite) {
+        let params = &self.caller.params;
         if s.is_mutated() {
             self.mutations += 1;
         }
+        else if s.n_reads > 0 && s.n_reads < params.min_depth {
+            self.excluded_low_depth += 1;
+        }
+        else if params.max_depth.is_some_and(|max| s.n_reads > max) {
+            self.excluded_high_depth += 1;
+        }
         else if s.n_reads > 0 {
             self.sites_covered += 1;
             self.max_depth = std::cmp::max(self.max_depth, s.n_reads);
             self.total_c += s.n_meth();
             self.total_t += s.n_reads - s.n_meth();
             self.mean_agg += s.meth;
-            let mut lower: f64 = 0.0;
-            let mut upper: f64 = 0.0;
-            // ADS: (below) replace this with some way to avoid all
-            // the re-evaluation of the Gaussian stuff.
-            wilson_ci_for_binomial(LevelsCounter::ALPHA, s.n_reads, s.meth,
-                                   &mut lower, &mut upper);
-            if lower > 0.5 {
-                self.called_meth += 1;
+            if let Some(spread) = &mut self.meth_spread {
+                spread.add(s.meth);
+            }
+            if let Some(sketch) = &mut self.meth_sketch {
+                sketch.add(s.meth);
             }
-            if upper < 0.5 {
-                self.called_unmeth += 1;
+            match self.caller.call(s.n_reads, s.n_meth(), s.meth) {
+                Call::Meth => self.called_meth += 1,
+                Call::Unmeth => self.called_unmeth += 1,
+                Call::Neither => {}
             }
+            if let Some(hist) = &mut self.meth_histogram {
+                hist.add(s.n_reads, s.meth);
+            }
+        }
+        if let Some(hist) = &mut self.depth_histogram {
+            hist.add(s.n_reads);
         }
         self.total_sites += 1;
     }
@@ -130,8 +495,6 @@ impl LevelsCounter {
         self.mean_agg/(self.sites_covered as f64)
     }
 
-    const ALPHA: f64 = 0.05;
-
     pub fn set_derived_values(&mut self) {
         self.coverage = self.get_coverage();
         self.sites_covered_fraction =
@@ -155,6 +518,31 @@ impl LevelsCounter {
         else {
             self.fractional_meth = 0.0;
         }
+
+        // otherwise as read back from output, or not known
+        if let Some(spread) = &self.meth_spread {
+            self.meth_variance = spread.variance();
+        }
+        self.meth_sd = self.meth_variance.sqrt();
+        if let Some(sketch) = &self.meth_sketch {
+            self.meth_median = sketch.quantile(0.5);
+            self.meth_q25 = sketch.quantile(0.25);
+            self.meth_q75 = sketch.quantile(0.75);
+        }
+
+        if let Some(hist) = &mut self.depth_histogram {
+            hist.set_derived_values();
+        }
+        if let Some(hist) = &mut self.meth_histogram {
+            hist.set_derived_values();
+        }
+    }
+}
+
+
+impl std::ops::AddAssign<&LevelsCounter> for LevelsCounter {
+    fn add_assign(&mut self, other: &LevelsCounter) {
+        self.merge(other);
     }
 }
 
@@ -169,93 +557,808 @@ impl std::fmt::Display for LevelsCounter {
 
 // ADS: I'm using this struct because it makes it more convenient to
 // have the desired indentation
-#[derive(Default,Serialize,Deserialize)]
-struct LC {
-    cytosine: LevelsCounter,
-    cpg: LevelsCounter,
-    cpg_symmetric: LevelsCounter,
-    chh: LevelsCounter,
-    ccg: LevelsCounter,
-    cxg: LevelsCounter,
+/// The levels counters for each cytosine context
+#[derive(Default,Clone,Serialize,Deserialize)]
+pub struct LevelsSummary {
+    pub cytosine: LevelsCounter,
+    pub cpg: LevelsCounter,
+    pub cpg_symmetric: LevelsCounter,
+    pub chh: LevelsCounter,
+    pub ccg: LevelsCounter,
+    pub cxg: LevelsCounter,
 }
 
-pub fn run_mlevels(
-    verbose: bool,
-    input: &String,
-    output: &String,
-) {
 
-    // setup the input file
-    let in_file = File::open(input).unwrap_or_else(|err| {
-        eprintln!("{err}");
-        process::exit(1);
-    });
-    let in_file = BufReader::new(in_file);
+impl LevelsSummary {
+    pub fn new(params: &ContextParams) -> LevelsSummary {
+        // contexts with the same parameters share a caller and its
+        // table
+        let mut callers: Vec<MethCaller> = Vec::new();
+        let mut counter = |params: CallParams| {
+            let caller = match callers.iter().find(|c| c.params == params) {
+                Some(caller) => caller.clone(),
+                None => {
+                    callers.push(MethCaller::new(params));
+                    callers[callers.len() - 1].clone()
+                }
+            };
+            LevelsCounter::with_caller(caller)
+        };
+        LevelsSummary {
+            cytosine: counter(params.cytosine),
+            cpg: counter(params.cpg),
+            cpg_symmetric: counter(params.cpg_symmetric),
+            chh: counter(params.chh),
+            ccg: counter(params.ccg),
+            cxg: counter(params.cxg),
+        }
+    }
+    /// The calling parameters used by each counter
+    pub fn params(&self) -> ContextParams {
+        ContextParams {
+            cytosine: self.cytosine.params(),
+            cpg: self.cpg.params(),
+            cpg_symmetric: self.cpg_symmetric.params(),
+            chh: self.chh.params(),
+            ccg: self.ccg.params(),
+            cxg: self.cxg.params(),
+        }
+    }
+    /// Each counter along with the name of its context
+    pub fn counters(&self) -> [(&'static str, &LevelsCounter); 6] {
+        let names = ContextParams::CONTEXTS;
+        [(names[0], &self.cytosine),
+         (names[1], &self.cpg),
+         (names[2], &self.cpg_symmetric),
+         (names[3], &self.chh),
+         (names[4], &self.ccg),
+         (names[5], &self.cxg)]
+    }
+    /// Also find the variance and quantiles of the levels in every
+    /// context
+    pub fn with_spread(self) -> LevelsSummary {
+        LevelsSummary {
+            cytosine: self.cytosine.with_spread(),
+            cpg: self.cpg.with_spread(),
+            cpg_symmetric: self.cpg_symmetric.with_spread(),
+            chh: self.chh.with_spread(),
+            ccg: self.ccg.with_spread(),
+            cxg: self.cxg.with_spread(),
+        }
+    }
+    /// Also count the sites with each depth in every context
+    pub fn with_depth_histogram(self, cap: u64) -> LevelsSummary {
+        LevelsSummary {
+            cytosine: self.cytosine.with_depth_histogram(cap),
+            cpg: self.cpg.with_depth_histogram(cap),
+            cpg_symmetric: self.cpg_symmetric.with_depth_histogram(cap),
+            chh: self.chh.with_depth_histogram(cap),
+            ccg: self.ccg.with_depth_histogram(cap),
+            cxg: self.cxg.with_depth_histogram(cap),
+        }
+    }
+    /// Also count the sites in bins of methylation level in every
+    /// context
+    pub fn with_meth_histogram(
+        self,
+        n_bins: usize,
+        min_depth: u64,
+    ) -> LevelsSummary {
+        let with = |lc: LevelsCounter| {
+            lc.with_meth_histogram(n_bins, min_depth)
+        };
+        LevelsSummary {
+            cytosine: with(self.cytosine),
+            cpg: with(self.cpg),
+            cpg_symmetric: with(self.cpg_symmetric),
+            chh: with(self.chh),
+            ccg: with(self.ccg),
+            cxg: with(self.cxg),
+        }
+    }
+    /// Add the counts for each context from another summary
+    pub fn merge(&mut self, other: &LevelsSummary) {
+        self.cytosine += &other.cytosine;
+        self.cpg += &other.cpg;
+        self.cpg_symmetric += &other.cpg_symmetric;
+        self.chh += &other.chh;
+        self.ccg += &other.ccg;
+        self.cxg += &other.cxg;
+    }
+    // update the counters for the context of the site, which must be
+    // known; symmetric CpGs are counted separately
+    fn update(&mut self, site: &MSite) {
+        self.cytosine.update(site);
+        if site.is_cpg() {
+            self.cpg.update(site);
+        }
+        else if site.is_chh() {
+            self.chh.update(site);
+        }
+        else if site.is_ccg() {
+            self.ccg.update(site);
+        }
+        else if site.is_cxg() {
+            self.cxg.update(site);
+        }
+    }
+    // update for a CpG site merged with its mate
+    fn update_symmetric(&mut self, pair: &MSite) {
+        self.cpg_symmetric.update(pair);
+    }
+    // the sketches for the quantiles are only in the output if requested
+    fn drop_sketches(&mut self) {
+        self.cytosine.meth_sketch = None;
+        self.cpg.meth_sketch = None;
+        self.cpg_symmetric.meth_sketch = None;
+        self.chh.meth_sketch = None;
+        self.ccg.meth_sketch = None;
+        self.cxg.meth_sketch = None;
+    }
+    pub fn set_derived_values(&mut self) {
+        self.cytosine.set_derived_values();
+        self.cpg.set_derived_values();
+        self.cpg_symmetric.set_derived_values();
+        self.chh.set_derived_values();
+        self.ccg.set_derived_values();
+        self.cxg.set_derived_values();
+    }
+}
+
+
+impl std::fmt::Display for LevelsSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let yaml = serde_yaml::to_string(&self).unwrap();
+        write!(f, "{yaml}")
+    }
+}
 
-    // setup the output stream
-    let mut out = File::create(output).unwrap_or_else(|err| {
-        eprintln!("{err}");
-        process::exit(1);
-    });
 
-    // includes all counters
-    let mut lc: LC = Default::default();
+/// The levels for one chromosome
+#[derive(Clone,Serialize,Deserialize)]
+pub struct ChromLevels {
+    pub chrom: String,
+    #[serde(flatten)]
+    pub levels: LevelsSummary,
+}
 
-    let mut prev_site = MSite::new();
-    let mut prev_is_cpg = false;
 
-    // iterate over lines in the counts file
-    for line in in_file.lines() {
-        let line = line.unwrap();
+/// What is written to the output: the levels along with the
+/// parameters used to compute them, and optionally the levels for
+/// each chromosome
+#[derive(Serialize,Deserialize)]
+pub struct LevelsReport {
+    /// Where the levels came from, if recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<RunMetadata>,
+    #[serde(flatten)]
+    pub summary: LevelsSummary,
+    /// Bisulfite conversion estimated from non-CpG sites, if any have
+    /// coverage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversion: Option<ConversionRates>,
+    #[serde(default)]
+    pub parameters: ContextParams,
+    /// Problems with the order of the sites, when counted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort_problems: Option<SortProblems>,
+    /// Lines that were not sites, when skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped_lines: Option<SkippedLines>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chromosomes: Vec<ChromLevels>,
+    /// Lines of input read for the levels
+    #[serde(skip)]
+    pub lines: u64,
+}
 
-        // make the current line into a site
-        let site = MSite::build(&line).unwrap_or_else(|_err| {
-            eprintln!("failed parsing site: {}", line);
-            process::exit(1);
-        });
 
-        if site.chrom != prev_site.chrom && verbose {
-            eprintln!("PROCESSING:\t{}",
-                      std::str::from_utf8(&site.chrom).unwrap());
+impl LevelsReport {
+    pub fn new(summary: LevelsSummary) -> LevelsReport {
+        let parameters = summary.params();
+        LevelsReport {
+            metadata: None,
+            summary,
+            conversion: None,
+            parameters,
+            sort_problems: None,
+            skipped_lines: None,
+            chromosomes: Vec::new(),
+            lines: 0,
+        }
+    }
+    /// Add the counts from another report, which must have used the
+    /// same parameters. Chromosomes in both are combined. The metadata
+    /// no longer applies and is dropped. The derived values must be set
+    /// again after.
+    pub fn merge(&mut self, other: &LevelsReport) -> Result<(), String> {
+        if self.parameters != other.parameters {
+            return Err("computed with different parameters".to_string());
+        }
+        self.metadata = None;
+        self.lines += other.lines;
+        self.summary.merge(&other.summary);
+        if let Some(other) = &other.sort_problems {
+            self.sort_problems.get_or_insert_with(Default::default)
+                .merge(other);
+        }
+        if let Some(other) = &other.skipped_lines {
+            self.skipped_lines.get_or_insert_with(Default::default)
+                .merge(other);
+        }
+        for chrom in &other.chromosomes {
+            match self.chromosomes.iter_mut().find(|c| c.chrom == chrom.chrom) {
+                Some(existing) => existing.levels.merge(&chrom.levels),
+                None => self.chromosomes.push(chrom.clone()),
+            }
         }
+        Ok(())
+    }
+    /// Remove the fine bins of levels kept for the quantiles, which
+    /// are only needed to merge the report with others
+    pub fn drop_sketches(&mut self) {
+        self.summary.drop_sketches();
+        for chrom in &mut self.chromosomes {
+            chrom.levels.drop_sketches();
+        }
+    }
+    pub fn set_derived_values(&mut self) {
+        self.summary.set_derived_values();
+        self.conversion =
+            ConversionRates::new(&self.summary, &self.parameters);
+        for chrom in &mut self.chromosomes {
+            chrom.levels.set_derived_values();
+        }
+    }
+}
 
-        // do this first, because the "add" for CpG can invalidate it
-        lc.cytosine.update(&site);
 
+/// Settings for a run of mlevels
+pub struct LevelsConfig {
+    pub verbose: bool,
+    /// Format of the output
+    pub format: OutputFormat,
+    /// Threads used for decompressing input, or for counting chunks of
+    /// an uncompressed file
+    pub threads: usize,
+    /// Memory map uncompressed input files instead of reading them
+    pub mmap: bool,
+    /// Whether sites out of order stop the run or are counted
+    pub sort_check: SortCheck,
+    /// Skip and count lines that are not sites of a known context,
+    /// instead of stopping at the first
+    pub skip_bad_lines: bool,
+    /// Record the input, version and times in the output
+    pub metadata: bool,
+    /// Histograms of depth in each context, with this cap
+    pub depth_histogram: Option<u64>,
+    /// Histograms of methylation level in each context, with this many
+    /// bins
+    pub meth_histogram: Option<usize>,
+    /// Minimum depth of the sites in the methylation level histograms
+    pub meth_histogram_min_depth: u64,
+    /// Keep the fine bins of levels for the quantiles in the output, so
+    /// they can be found again when outputs are merged
+    pub keep_sketch: bool,
+    pub params: ContextParams,
+    /// Also compute levels separately for each chromosome
+    pub by_chrom: bool,
+    /// File for a table of the levels for each chromosome
+    pub chrom_table: Option<String>,
+    /// BED file of regions; only sites inside are counted
+    pub regions: Option<String>,
+    /// BED file of regions; sites inside are not counted
+    pub exclude: Option<String>,
+    /// BED file of regions to summarize individually
+    pub roi: Option<String>,
+    /// File for the summary of each region in `roi`
+    pub roi_out: Option<String>,
+    /// Context of the sites summarized for each region
+    pub roi_context: String,
+    /// Size of genomic bins to summarize, if any
+    pub bin_size: Option<u64>,
+    /// File for the table of levels in each bin
+    pub bins_out: Option<String>,
+    /// File for a bedGraph of levels in each bin
+    pub bins_bedgraph: Option<String>,
+    /// File with the length of each chromosome, to end the last bin
+    pub chrom_sizes: Option<String>,
+    /// Context of the sites in the bedGraph
+    pub bin_context: String,
+    /// Use the unweighted mean in the bedGraph
+    pub bedgraph_unweighted: bool,
+    /// Prefix for MultiQC custom content files
+    pub multiqc_prefix: Option<String>,
+    /// Name of the sample for MultiQC, by default from the input
+    pub sample_name: Option<String>,
+}
+
+
+impl Default for LevelsConfig {
+    fn default() -> LevelsConfig {
+        LevelsConfig {
+            verbose: false,
+            format: OutputFormat::Yaml,
+            threads: 1,
+            mmap: true,
+            sort_check: SortCheck::Strict,
+            skip_bad_lines: false,
+            metadata: false,
+            depth_histogram: None,
+            meth_histogram: None,
+            meth_histogram_min_depth: 1,
+            keep_sketch: false,
+            params: Default::default(),
+            by_chrom: false,
+            chrom_table: None,
+            regions: None,
+            exclude: None,
+            roi: None,
+            roi_out: None,
+            roi_context: "cpg".to_string(),
+            bin_size: None,
+            bins_out: None,
+            bins_bedgraph: None,
+            chrom_sizes: None,
+            bin_context: "cpg".to_string(),
+            bedgraph_unweighted: false,
+            multiqc_prefix: None,
+            sample_name: None,
+        }
+    }
+}
+
+
+// Holds the previous site so CpGs can be paired with their mates on
+// the opposite strand; sites must arrive in sorted order.
+struct SiteCounter {
+    verbose: bool,
+    by_chrom: bool,
+    // counters with no sites yet, to copy for each chromosome
+    empty: LevelsSummary,
+    summary: LevelsSummary,
+    chromosomes: Vec<ChromLevels>,
+    // the entry in chromosomes for the sites being counted
+    chrom_idx: Option<usize>,
+    roi: Option<RegionLevels>,
+    bins: Option<BinLevels>,
+    sort: SortChecker,
+    // bad lines, if they are skipped
+    skipped: Option<SkippedLines>,
+    n_lines: u64,
+    prev_site: MSite,
+    // the previous site is a CpG that is not yet paired
+    prev_is_cpg: bool,
+}
+
+
+impl SiteCounter {
+    fn new(config: &LevelsConfig) -> Result<SiteCounter, MlevelsError> {
+        // regions and bins have no histograms or spread of levels, as
+        // there are many
+        let plain = LevelsSummary::new(&config.params);
+        let mut empty = plain.clone().with_spread();
+        empty = match config.depth_histogram {
+            Some(cap) => empty.with_depth_histogram(cap),
+            None => empty,
+        };
+        if let Some(n_bins) = config.meth_histogram {
+            empty = empty.with_meth_histogram(n_bins,
+                                              config.meth_histogram_min_depth);
+        }
+        let roi = match (&config.roi, &config.roi_out) {
+            (Some(roi), Some(roi_out)) => {
+                Some(RegionLevels::new(roi, roi_out, &config.roi_context,
+                                       &plain)?)
+            }
+            _ => None,
+        };
+        let bins = config.bin_size
+            .map(|bin_size| {
+                BinLevels::new(bin_size, &config.bins_out,
+                               &config.bins_bedgraph, &config.chrom_sizes,
+                               &config.bin_context,
+                               config.bedgraph_unweighted, &plain)
+            })
+            .transpose()?;
+        Ok(SiteCounter {
+            verbose: config.verbose,
+            by_chrom: config.by_chrom || config.chrom_table.is_some(),
+            summary: empty.clone(),
+            empty,
+            chromosomes: Vec::new(),
+            chrom_idx: None,
+            roi,
+            bins,
+            sort: SortChecker::new(config.sort_check),
+            skipped: config.skip_bad_lines.then(SkippedLines::default),
+            n_lines: 0,
+            prev_site: MSite::new(),
+            prev_is_cpg: false,
+        })
+    }
+
+    // returns false if the site is not of any known context; a site
+    // that is counted is swapped with the previous one, so its buffers
+    // can be reused for the next site
+    fn add(&mut self, site: &mut MSite) -> Result<bool, MlevelsError> {
+        if !(site.is_cpg() || site.is_chh() || site.is_ccg() || site.is_cxg()) {
+            return Ok(false);
+        }
+
+        if site.chrom != self.prev_site.chrom {
+            if self.verbose {
+                eprintln!("PROCESSING:\t{}",
+                          String::from_utf8_lossy(&site.chrom));
+            }
+            if self.by_chrom {
+                // a chromosome seen before, if the input is not sorted,
+                // continues with the same entry
+                let chrom = String::from_utf8_lossy(&site.chrom);
+                let idx = self.chromosomes.iter()
+                    .position(|c| c.chrom == chrom)
+                    .unwrap_or_else(|| {
+                        self.chromosomes.push(ChromLevels {
+                            chrom: chrom.into_owned(),
+                            levels: self.empty.clone(),
+                        });
+                        self.chromosomes.len() - 1
+                    });
+                self.chrom_idx = Some(idx);
+            }
+        }
+
+        let mut completes_pair = false;
         if site.is_cpg() {
-            lc.cpg.update(&site);
-            if prev_is_cpg && prev_site.is_mate_of(&site) {
-                prev_site.add(&site);
-                lc.cpg_symmetric.update(&prev_site);
-                prev_is_cpg = false;
+            if self.prev_is_cpg && self.prev_site.is_mate_of(site) {
+                self.prev_site.add(site);
+                completes_pair = true;
+                self.prev_is_cpg = false;
             }
             else {
-                prev_is_cpg = true;
+                self.prev_is_cpg = true;
             }
         }
-        else if site.is_chh() {
-            lc.chh.update(&site);
+        else {
+            self.prev_is_cpg = false;
         }
-        else if site.is_ccg() {
-            lc.ccg.update(&site);
+        let symmetric = completes_pair.then_some(&self.prev_site);
+
+        self.summary.update(site);
+        if let Some(pair) = symmetric {
+            self.summary.update_symmetric(pair);
         }
-        else if site.is_cxg() {
-            lc.cxg.update(&site);
+        if let Some(chrom) = self.chrom_idx.map(|i| &mut self.chromosomes[i]) {
+            chrom.levels.update(site);
+            if let Some(pair) = symmetric {
+                chrom.levels.update_symmetric(pair);
+            }
         }
-        else {
-            eprintln!("bad site type: {}", line);
-            process::exit(1);
+        if let Some(roi) = &mut self.roi {
+            roi.update(site, symmetric)?;
+        }
+        if let Some(bins) = &mut self.bins {
+            bins.update(site, symmetric)?;
+        }
+
+        std::mem::swap(&mut self.prev_site, site);
+        Ok(true)
+    }
+
+    fn finish(mut self) -> Result<LevelsReport, MlevelsError> {
+        if let Some(roi) = self.roi.take() {
+            roi.finish()?;
+        }
+        if let Some(bins) = self.bins.take() {
+            bins.finish()?;
+        }
+        let mut report = LevelsReport::new(self.summary);
+        report.sort_problems = self.sort.problems();
+        report.skipped_lines = self.skipped;
+        report.lines = self.n_lines;
+        report.chromosomes = self.chromosomes;
+        report.set_derived_values();
+        Ok(report)
+    }
+}
+
+
+/// Compute levels from the lines of a counts file, which must be
+/// sorted.
+pub fn levels_from_reader<R: BufRead>(
+    config: &LevelsConfig,
+    reader: R,
+) -> Result<LevelsSummary, MlevelsError> {
+    Ok(report_from_reader(config, reader)?.summary)
+}
+
+
+/// Compute levels from the lines of a counts file, which must be
+/// sorted, along with everything else requested in the config.
+pub fn report_from_reader<R: BufRead>(
+    config: &LevelsConfig,
+    reader: R,
+) -> Result<LevelsReport, MlevelsError> {
+
+    let mut counter = SiteCounter::new(config)?;
+    let mut filter = SiteFilter::from_config(config)?;
+    count_lines(&mut counter, &mut filter, reader)?;
+    counter.finish()
+}
+
+
+/// Compute levels from counts data held in memory, e.g., a mapped
+/// file, scanning the lines without copying them.
+pub fn report_from_slice(
+    config: &LevelsConfig,
+    data: &[u8],
+) -> Result<LevelsReport, MlevelsError> {
+
+    let mut counter = SiteCounter::new(config)?;
+    let mut filter = SiteFilter::from_config(config)?;
+    count_slice(&mut counter, &mut filter, data)?;
+    counter.finish()
+}
+
+
+// Add the site on one line of a counts file, parsing it into `site`
+fn count_line(
+    counter: &mut SiteCounter,
+    filter: &mut Option<SiteFilter>,
+    line: &[u8],
+    line_number: u64,
+    site: &mut MSite,
+) -> Result<(), MlevelsError> {
+
+    counter.n_lines += 1;
+    if line.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(());
+    }
+    let to_line = |line: &[u8]| {
+        String::from_utf8_lossy(line).trim_end_matches(['\n', '\r'])
+            .to_string()
+    };
+
+    // make the current line into a site
+    if let Err(err) = parse_site(line, site) {
+        let Some(skipped) = &mut counter.skipped else {
+            let line = to_line(line);
+            return Err(MlevelsError::Parse { line_number, line });
+        };
+        skipped.add(err.name(), line_number);
+        return Ok(());
+    }
+    counter.sort.check(site, line_number)?;
+
+    if let Some(filter) = filter {
+        if !filter.keep(&site.chrom, site.pos) {
+            return Ok(());
         }
+    }
 
-        prev_site = site;
+    if !counter.add(site)? {
+        match &mut counter.skipped {
+            Some(skipped) => skipped.add("unknown_context", line_number),
+            None => {
+                return Err(MlevelsError::UnknownContext {
+                    line_number,
+                    line: to_line(line),
+                });
+            }
+        }
     }
+    Ok(())
+}
+
+
+// Add the sites from the lines of a counts file, returning the number
+// of lines; line numbers in errors start from 1 for this reader.
+fn count_lines<R: BufRead>(
+    counter: &mut SiteCounter,
+    filter: &mut Option<SiteFilter>,
+    mut reader: R,
+) -> Result<u64, MlevelsError> {
 
-    lc.cytosine.set_derived_values();
-    lc.cpg.set_derived_values();
-    lc.cpg_symmetric.set_derived_values();
-    lc.chh.set_derived_values();
-    lc.ccg.set_derived_values();
-    lc.cxg.set_derived_values();
+    let mut n_lines = 0;
+    // one buffer for every line and one site to parse it into
+    let mut line = Vec::new();
+    let mut site = MSite::new();
 
-    write!(out, "{}", serde_yaml::to_string(&lc).unwrap()).unwrap();
+    // iterate over lines in the counts file
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        n_lines += 1;
+        count_line(counter, filter, &line, n_lines, &mut site)?;
+    }
+
+    Ok(n_lines)
+}
+
+
+// As `count_lines`, for lines in memory
+fn count_slice(
+    counter: &mut SiteCounter,
+    filter: &mut Option<SiteFilter>,
+    data: &[u8],
+) -> Result<u64, MlevelsError> {
+
+    let mut n_lines = 0;
+    let mut site = MSite::new();
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        n_lines += 1;
+        count_line(counter, filter, line, n_lines, &mut site)?;
+    }
+    Ok(n_lines)
+}
+
+
+/// Compute levels from sites that are already parsed, in sorted
+/// order. For a site of unknown context or out of order, the error is
+/// `MlevelsError::Site`, with the number of the site in the iterator,
+/// from 1, and its location.
+pub fn levels_from_sites<I: IntoIterator<Item = MSite>>(
+    config: &LevelsConfig,
+    sites: I,
+) -> Result<LevelsSummary, MlevelsError> {
+
+    let mut counter = SiteCounter::new(config)?;
+    let mut filter = SiteFilter::from_config(config)?;
+
+    for (site_idx, mut site) in sites.into_iter().enumerate() {
+        let site_number = (site_idx + 1) as u64;
+        counter.sort.check(&site, site_number).map_err(|err| match err {
+            MlevelsError::Unsorted { site, problem, .. } => {
+                MlevelsError::Site { site_number, site, problem }
+            }
+            err => err,
+        })?;
+        if let Some(filter) = &mut filter {
+            if !filter.keep(&site.chrom, site.pos) {
+                continue;
+            }
+        }
+        if !counter.add(&mut site)? {
+            return Err(MlevelsError::Site {
+                site_number,
+                site: format!("{}:{}", String::from_utf8_lossy(&site.chrom),
+                              site.pos),
+                problem: "bad site type".to_string(),
+            });
+        }
+    }
+
+    Ok(counter.finish()?.summary)
+}
+
+
+/// Compute levels for the counts file `input` and write them in the
+/// configured format to `output`, where "-" means standard input or
+/// output.
+pub fn run_mlevels(
+    config: &LevelsConfig,
+    input: &str,
+    output: &str,
+) -> Result<(), MlevelsError> {
+
+    let timer = RunTimer::start();
+
+    // an uncompressed file is mapped, and can be split into chunks for
+    // threads; otherwise setup the input file, which may be compressed
+    // and is hashed as it is read if the metadata is recorded
+    let mapped = if config.mmap { map_counts(input)? } else { None };
+    let mut digest = None;
+    let in_file = match mapped {
+        Some(_) => None,
+        None if config.metadata => {
+            let reader = HashingReader::new(raw_input(input)?);
+            digest = Some(reader.digest());
+            Some(decompressing_reader(reader, config.threads)?)
+        }
+        None => Some(open_counts(input, config.threads)?),
+    };
+
+    // setup the output stream; "-" means standard output
+    let mut out: Box<dyn Write> = if output == "-" {
+        Box::new(std::io::stdout().lock())
+    }
+    else {
+        Box::new(File::create(output).map_err(MlevelsError::Write)?)
+    };
+
+    let split = config.threads > 1 && config.roi.is_none() &&
+        config.bin_size.is_none();
+    let mut lc = match (&mapped, in_file) {
+        (Some(data), _) if split => report_from_chunks(config, data)?,
+        (Some(data), _) => report_from_slice(config, data)?,
+        (None, in_file) => {
+            report_from_reader(config, in_file.expect("input is opened"))?
+        }
+    };
+
+    if config.metadata {
+        let sha256 = match (&mapped, &digest) {
+            (Some(data), _) => sha256_hex(data),
+            (None, digest) => digest.as_ref().expect("input is hashed").hex(),
+        };
+        let size = std::fs::metadata(input).ok()
+            .filter(|meta| input != "-" && meta.is_file())
+            .map(|meta| meta.len());
+        let input = InputMetadata {
+            path: input.to_string(),
+            size,
+            sha256,
+            lines: lc.lines,
+        };
+        lc.metadata = Some(timer.finish(config, input));
+    }
+    if !config.keep_sketch {
+        lc.drop_sketches();
+    }
+
+    if let Some(chrom_table) = &config.chrom_table {
+        let mut table = File::create(chrom_table).map_err(MlevelsError::Write)?;
+        write_chrom_table(&mut table, &lc.chromosomes)
+            .map_err(MlevelsError::Write)?;
+        // only in the YAML if requested there as well
+        if !config.by_chrom {
+            lc.chromosomes.clear();
+        }
+    }
+
+    if let Some(prefix) = &config.multiqc_prefix {
+        let sample = config.sample_name.clone()
+            .unwrap_or_else(|| sample_name_for(input));
+        write_multiqc(&lc, &sample, prefix)?;
+    }
+
+    config.format.writer().write(&lc, &mut out)?;
+    out.flush().map_err(MlevelsError::Write)?;
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contexts_share_callers() {
+        let mut params = ContextParams::default();
+        params.chh.alpha = 0.01;
+        let summary = LevelsSummary::new(&params);
+        let table = |lc: &LevelsCounter| Arc::clone(&lc.caller.table);
+        assert!(Arc::ptr_eq(&table(&summary.cpg), &table(&summary.ccg)));
+        assert!(!Arc::ptr_eq(&table(&summary.cpg), &table(&summary.chh)));
+        assert!(Arc::ptr_eq(&MethCaller::default().table,
+                            &MethCaller::default().table));
+    }
+
+    // levels are not always n_meth/n_reads, e.g., written with six
+    // digits, or from other tools that merge reads differently
+    #[test]
+    fn table_calls_match_computed_calls() {
+        let caller = MethCaller::new(Default::default());
+        for n_reads in 1..=MethCaller::MAX_TABLE_DEPTH + 2 {
+            for i in 0..=1000 {
+                let meth = (i as f64)/1000.0;
+                let n_meth = (meth*n_reads as f64).round() as u64;
+                assert_eq!(caller.call(n_reads, n_meth, meth),
+                           caller.compute_call(n_reads, meth),
+                           "{meth} of {n_reads} reads");
+            }
+            for n_meth in 0..=n_reads {
+                let exact = (n_meth as f64)/(n_reads as f64);
+                let written: f64 = format!("{exact:.6}").parse().unwrap();
+                for meth in [exact, written] {
+                    assert_eq!(caller.call(n_reads, n_meth, meth),
+                               caller.compute_call(n_reads, meth),
+                               "{n_meth}/{n_reads} as {meth}");
+                }
+            }
+        }
+    }
 }
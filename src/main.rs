@@ -23,38 +23,414 @@
  * SOFTWARE.
  */
 
-use clap::Parser;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 use std::process::ExitCode;
 
+use mlevels::{CallParams, ContextParams, LevelsConfig, MlevelsError};
+use mlevels::{OutputFormat, Sample, SortCheck};
+
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None,
+          args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Counts file
-    #[arg(short, long)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Counts file ("-" or omitted for standard input)
+    #[arg(short, long, default_value = "-")]
     counts: String,
 
-    /// Output file
+    /// Output file ("-" or omitted for standard output)
+    #[arg(short, long, default_value = "-")]
+    out: String,
+
+    /// Output format (tsv has only the genome-wide levels)
+    #[arg(short, long, default_value = "yaml",
+          value_parser = PossibleValuesParser::new(OutputFormat::NAMES)
+              .map(|s| s.parse::<OutputFormat>().unwrap()))]
+    format: OutputFormat,
+
+    /// Threads for decompressing BGZF input, or for counting chunks of
+    /// an uncompressed file
+    #[arg(short, long, default_value_t = 1)]
+    threads: usize,
+
+    /// Read uncompressed counts files instead of memory mapping them
+    #[arg(long)]
+    no_mmap: bool,
+
+    /// Stop at sites out of order, duplicated or on a chromosome seen
+    /// before (strict), or count them in the output (lenient)
+    #[arg(long, default_value = "strict",
+          value_parser = PossibleValuesParser::new(SortCheck::NAMES)
+              .map(|s| s.parse::<SortCheck>().unwrap()))]
+    sort_check: SortCheck,
+
+    /// Skip lines that are not sites of a known context, e.g., headers
+    /// or truncated lines, and count them in the output
+    #[arg(long)]
+    skip_bad_lines: bool,
+
+    /// Record the input file, its checksum, the mlevels version and the
+    /// time taken in the output
+    #[arg(long)]
+    metadata: bool,
+
+    /// Report a histogram of depths for each context, with sites at or
+    /// above this depth (at most 10000) counted together
+    #[arg(long, value_name = "CAP",
+          value_parser = clap::value_parser!(u64).range(1..=10000))]
+    depth_histogram: Option<u64>,
+
+    /// Report a histogram of methylation levels for each context, with
+    /// this many bins, and the fractions of sites with low (< 0.2),
+    /// intermediate and high (> 0.8) levels
+    #[arg(long, value_name = "BINS",
+          value_parser = clap::value_parser!(u64).range(1..=1000))]
+    meth_histogram: Option<u64>,
+
+    /// Only include sites with at least this many reads in the
+    /// methylation level histograms
+    #[arg(long, value_name = "DEPTH", default_value_t = 1,
+          requires = "meth_histogram")]
+    meth_histogram_min_depth: u64,
+
+    /// Keep the fine bins of levels used for the quantiles in the
+    /// output, so the quantiles can be found again by merge
+    #[arg(long)]
+    keep_sketch: bool,
+
+    #[command(flatten)]
+    calling: CallingArgs,
+
+    /// Also report levels for each chromosome
+    #[arg(long)]
+    by_chrom: bool,
+
+    /// Write a table of levels for each chromosome to this file
+    #[arg(long, value_name = "FILE")]
+    chrom_table: Option<String>,
+
+    /// Only count sites inside regions of this BED file
+    #[arg(short, long, value_name = "BED")]
+    regions: Option<String>,
+
+    /// Do not count sites inside regions of this BED file
+    #[arg(short, long, value_name = "BED")]
+    exclude: Option<String>,
+
+    /// Summarize each region of this BED file separately
+    #[arg(long, value_name = "BED", requires = "roi_out")]
+    roi: Option<String>,
+
+    /// Output file for the summary of each region
+    #[arg(long, value_name = "FILE", requires = "roi")]
+    roi_out: Option<String>,
+
+    /// Context of the sites summarized for each region
+    #[arg(long, default_value = "cpg",
+          value_parser = PossibleValuesParser::new(ContextParams::CONTEXTS))]
+    roi_context: String,
+
+    /// Summarize levels in genomic bins of this size
+    #[arg(long, value_name = "SIZE",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    bin_size: Option<u64>,
+
+    /// Output file for the table of levels in each bin
+    #[arg(long, value_name = "FILE", requires = "bin_size")]
+    bins_out: Option<String>,
+
+    /// Output file for a bedGraph of levels in each bin
+    #[arg(long, value_name = "FILE", requires = "bin_size")]
+    bins_bedgraph: Option<String>,
+
+    /// File with the length of each chromosome, like a UCSC chrom.sizes
+    /// file, so the last bin ends at the end of the chromosome
+    #[arg(long, value_name = "FILE", requires = "bin_size")]
+    chrom_sizes: Option<String>,
+
+    /// Context of the sites in the bedGraph
+    #[arg(long, default_value = "cpg",
+          value_parser = PossibleValuesParser::new(ContextParams::CONTEXTS))]
+    bin_context: String,
+
+    /// Use the unweighted mean methylation in the bedGraph
+    #[arg(long)]
+    bedgraph_unweighted: bool,
+
+    /// Write MultiQC custom content files starting with this prefix,
+    /// e.g., "qc/sample1." (use a different prefix for each sample)
+    #[arg(long, value_name = "PREFIX")]
+    multiqc: Option<String>,
+
+    /// Sample name for MultiQC (default: from the counts file name)
+    #[arg(long, value_name = "NAME")]
+    sample_name: Option<String>,
+
+    /// Be verbose
     #[arg(short, long)]
+    verbose: bool,
+}
+
+
+/// Parameters for calling sites methylated or unmethylated
+#[derive(clap::Args, Debug)]
+struct CallingArgs {
+    /// Significance level for calling sites methylated or unmethylated
+    #[arg(short, long, default_value_t = 0.05, value_parser = parse_alpha)]
+    alpha: f64,
+
+    /// Methylation level above which sites are called methylated, and
+    /// below which they are called unmethylated
+    #[arg(long, default_value_t = 0.5, value_parser = parse_threshold)]
+    threshold: f64,
+
+    /// Only count sites with at least this many reads as covered
+    #[arg(long, default_value_t = 1,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    min_depth: u64,
+
+    /// Exclude sites with more than this many reads, e.g., PCR hotspots
+    #[arg(long)]
+    max_depth: Option<u64>,
+
+    /// Significance level for one context, e.g., chh=0.01 (repeatable)
+    #[arg(long, value_name = "CONTEXT=ALPHA",
+          value_parser = parse_context_alpha)]
+    context_alpha: Vec<(String, f64)>,
+
+    /// Threshold for one context, e.g., chh=0.3 (repeatable)
+    #[arg(long, value_name = "CONTEXT=THRESHOLD",
+          value_parser = parse_context_threshold)]
+    context_threshold: Vec<(String, f64)>,
+}
+
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Combine mlevels outputs in YAML, e.g., for lanes or chromosomes
+    Merge(MergeArgs),
+    /// Levels for many counts files as one table, a row for each sample
+    Samples(SamplesArgs),
+}
+
+
+#[derive(clap::Args, Debug)]
+struct SamplesArgs {
+    /// Counts files; sample names are taken from the file names
+    #[arg(required_unless_present = "sample_sheet")]
+    counts: Vec<String>,
+
+    /// File with a sample name and counts file on each line, separated
+    /// by a tab
+    #[arg(short, long, value_name = "FILE", conflicts_with = "counts")]
+    sample_sheet: Option<String>,
+
+    /// Output file ("-" or omitted for standard output)
+    #[arg(short, long, default_value = "-")]
     out: String,
 
+    /// Number of counts files to process at the same time
+    #[arg(short, long, default_value_t = 1)]
+    threads: usize,
+
+    /// Read uncompressed counts files instead of memory mapping them
+    #[arg(long)]
+    no_mmap: bool,
+
+    /// Skip lines that are not sites of a known context, e.g., headers
+    /// or truncated lines
+    #[arg(long)]
+    skip_bad_lines: bool,
+
+    #[command(flatten)]
+    calling: CallingArgs,
+
     /// Be verbose
     #[arg(short, long)]
     verbose: bool,
 }
 
 
+fn run_samples(args: &SamplesArgs) -> Result<(), MlevelsError> {
+    let samples = match &args.sample_sheet {
+        Some(sample_sheet) => mlevels::read_sample_sheet(sample_sheet)?,
+        None => args.counts.iter().map(|c| Sample::from_path(c)).collect(),
+    };
+    let config = LevelsConfig {
+        verbose: args.verbose,
+        mmap: !args.no_mmap,
+        skip_bad_lines: args.skip_bad_lines,
+        params: args.calling.context_params(),
+        ..Default::default()
+    };
+    mlevels::run_samples(&config, &samples, args.threads, &args.out)
+}
+
+
+#[derive(clap::Args, Debug)]
+struct MergeArgs {
+    /// mlevels output files in YAML
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// Output file ("-" or omitted for standard output)
+    #[arg(short, long, default_value = "-")]
+    out: String,
+
+    /// Output format (tsv has only the genome-wide levels)
+    #[arg(short, long, default_value = "yaml",
+          value_parser = PossibleValuesParser::new(OutputFormat::NAMES)
+              .map(|s| s.parse::<OutputFormat>().unwrap()))]
+    format: OutputFormat,
+
+    /// Keep the fine bins of levels used for the quantiles in the
+    /// output, so it can be merged again
+    #[arg(long)]
+    keep_sketch: bool,
+}
+
+
+fn parse_alpha(s: &str) -> Result<f64, String> {
+    let alpha: f64 = s.parse().map_err(|_| format!("not a number: {s}"))?;
+    if !(alpha > 0.0 && alpha < 1.0) {
+        return Err(format!("must be strictly between 0 and 1: {s}"));
+    }
+    Ok(alpha)
+}
+
+
+fn parse_threshold(s: &str) -> Result<f64, String> {
+    let threshold: f64 = s.parse().map_err(|_| format!("not a number: {s}"))?;
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(format!("must be between 0 and 1: {s}"));
+    }
+    Ok(threshold)
+}
+
+
+fn parse_context_value(
+    s: &str,
+    parse_value: fn(&str) -> Result<f64, String>,
+) -> Result<(String, f64), String> {
+    let (context, value) = s.split_once('=')
+        .ok_or_else(|| format!("expected CONTEXT=VALUE: {s}"))?;
+    if !ContextParams::CONTEXTS.contains(&context) {
+        return Err(format!("unknown context {context} (expected one of {})",
+                           ContextParams::CONTEXTS.join(", ")));
+    }
+    Ok((context.to_string(), parse_value(value)?))
+}
+
+
+fn parse_context_alpha(s: &str) -> Result<(String, f64), String> {
+    parse_context_value(s, parse_alpha)
+}
+
+
+fn parse_context_threshold(s: &str) -> Result<(String, f64), String> {
+    parse_context_value(s, parse_threshold)
+}
+
+
+impl CallingArgs {
+    // clap cannot compare the values of two arguments, so this exits
+    // with a usage error for `cmd` like the ones it gives
+    fn check(&self, mut cmd: clap::Command) {
+        if self.max_depth.is_some_and(|max| max < self.min_depth) {
+            cmd.error(
+                ErrorKind::ArgumentConflict,
+                format!("--max-depth must be at least --min-depth ({})",
+                        self.min_depth),
+            ).exit();
+        }
+    }
+    fn context_params(&self) -> ContextParams {
+        let mut params = ContextParams::uniform(CallParams {
+            alpha: self.alpha,
+            threshold: self.threshold,
+            min_depth: self.min_depth,
+            max_depth: self.max_depth,
+        });
+        for (context, alpha) in &self.context_alpha {
+            params.get_mut(context).unwrap().alpha = *alpha;
+        }
+        for (context, threshold) in &self.context_threshold {
+            params.get_mut(context).unwrap().threshold = *threshold;
+        }
+        params
+    }
+}
+
+
+fn exit_status(result: Result<(), MlevelsError>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::from(err.exit_code())
+        }
+    }
+}
+
+
 fn main() -> ExitCode {
 
     let args = Args::parse();
+    args.calling.check(Args::command());
+
+    match &args.command {
+        Some(Command::Merge(merge)) => {
+            return exit_status(mlevels::run_merge(&merge.inputs, &merge.out,
+                                                  merge.format,
+                                                  merge.keep_sketch));
+        }
+        Some(Command::Samples(samples)) => {
+            let cmd = Args::command().find_subcommand("samples").cloned();
+            samples.calling.check(cmd.unwrap().bin_name("mlevels samples"));
+            return exit_status(run_samples(samples));
+        }
+        None => {}
+    }
+
     if args.verbose {
         eprintln!("[counts file={}]", args.counts);
         eprintln!("[output file={}]", args.out);
     }
 
-    mlevels::run_mlevels(args.verbose,
-                         &args.counts,
-                         &args.out);
+    let config = LevelsConfig {
+        verbose: args.verbose,
+        format: args.format,
+        threads: args.threads,
+        mmap: !args.no_mmap,
+        sort_check: args.sort_check,
+        skip_bad_lines: args.skip_bad_lines,
+        metadata: args.metadata,
+        depth_histogram: args.depth_histogram,
+        meth_histogram: args.meth_histogram.map(|n_bins| n_bins as usize),
+        meth_histogram_min_depth: args.meth_histogram_min_depth,
+        keep_sketch: args.keep_sketch,
+        params: args.calling.context_params(),
+        by_chrom: args.by_chrom,
+        chrom_table: args.chrom_table.clone(),
+        regions: args.regions.clone(),
+        exclude: args.exclude.clone(),
+        roi: args.roi.clone(),
+        roi_out: args.roi_out.clone(),
+        roi_context: args.roi_context.clone(),
+        bin_size: args.bin_size,
+        bins_out: args.bins_out.clone(),
+        bins_bedgraph: args.bins_bedgraph.clone(),
+        chrom_sizes: args.chrom_sizes.clone(),
+        bin_context: args.bin_context.clone(),
+        bedgraph_unweighted: args.bedgraph_unweighted,
+        multiqc_prefix: args.multiqc.clone(),
+        sample_name: args.sample_name.clone(),
+    };
 
-    ExitCode::SUCCESS
+    exit_status(mlevels::run_mlevels(&config, &args.counts, &args.out))
 }
@@ -0,0 +1,82 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::fs::File;
+use std::io::{BufReader, Write};
+
+use crate::{LevelsReport, MlevelsError, OutputFormat};
+
+
+/// Read a report previously written by mlevels in YAML
+pub fn read_report(path: &str) -> Result<LevelsReport, MlevelsError> {
+    let reader = BufReader::new(File::open(path)?);
+    serde_yaml::from_reader(reader).map_err(|err| MlevelsError::Report {
+        path: path.to_string(),
+        message: err.to_string(),
+    })
+}
+
+
+/// Combine reports from `inputs`, e.g., computed for separate lanes or
+/// chromosomes, and write the result to `output` ("-" for standard
+/// output). Counts are added, the maximum depth is the largest, and
+/// the derived values are computed again from the combined counts. The
+/// quantiles of levels are only found again if every input has the
+/// sketches for them, which are kept in the result if `keep_sketch`.
+pub fn run_merge(
+    inputs: &[String],
+    output: &str,
+    format: OutputFormat,
+    keep_sketch: bool,
+) -> Result<(), MlevelsError> {
+
+    let mut merged: Option<LevelsReport> = None;
+    for input in inputs {
+        let report = read_report(input)?;
+        match &mut merged {
+            None => merged = Some(report),
+            Some(merged) => merged.merge(&report).map_err(|message| {
+                MlevelsError::Report { path: input.clone(), message }
+            })?,
+        }
+    }
+    let mut merged = match merged {
+        Some(merged) => merged,
+        None => return Ok(()),
+    };
+    merged.set_derived_values();
+    if !keep_sketch {
+        merged.drop_sketches();
+    }
+
+    let mut out: Box<dyn Write> = if output == "-" {
+        Box::new(std::io::stdout().lock())
+    }
+    else {
+        Box::new(File::create(output).map_err(MlevelsError::Write)?)
+    };
+    format.writer().write(&merged, &mut out)?;
+    out.flush().map_err(MlevelsError::Write)
+}
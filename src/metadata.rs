@@ -0,0 +1,198 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::{LevelsConfig, SortCheck};
+
+
+/// The counts file that levels were computed from
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct InputMetadata {
+    /// Path as given, "-" for standard input
+    pub path: String,
+    /// Size in bytes of the file as stored, if it is a regular file
+    pub size: Option<u64>,
+    /// SHA-256 of the bytes read, before any decompression
+    pub sha256: String,
+    pub lines: u64,
+}
+
+
+/// Settings that decide which sites and lines are counted, and what
+/// is summarized besides the levels
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct FilterMetadata {
+    pub regions: Option<String>,
+    pub exclude: Option<String>,
+    pub sort_check: SortCheck,
+    pub skip_bad_lines: bool,
+    /// BED file of regions summarized individually, and their context
+    #[serde(default)]
+    pub roi: Option<String>,
+    #[serde(default)]
+    pub roi_context: Option<String>,
+    /// Size of the genomic bins summarized, their context, and the
+    /// file with the chromosome lengths
+    #[serde(default)]
+    pub bin_size: Option<u64>,
+    #[serde(default)]
+    pub bin_context: Option<String>,
+    #[serde(default)]
+    pub chrom_sizes: Option<String>,
+    #[serde(default)]
+    pub depth_histogram: Option<u64>,
+    #[serde(default)]
+    pub meth_histogram: Option<usize>,
+    #[serde(default)]
+    pub meth_histogram_min_depth: Option<u64>,
+    #[serde(default)]
+    pub keep_sketch: bool,
+}
+
+
+impl FilterMetadata {
+    pub fn new(config: &LevelsConfig) -> FilterMetadata {
+        let roi = config.roi.as_ref().filter(|_| config.roi_out.is_some());
+        FilterMetadata {
+            regions: config.regions.clone(),
+            exclude: config.exclude.clone(),
+            sort_check: config.sort_check,
+            skip_bad_lines: config.skip_bad_lines,
+            roi: roi.cloned(),
+            roi_context: roi.map(|_| config.roi_context.clone()),
+            bin_size: config.bin_size,
+            bin_context: config.bin_size.map(|_| config.bin_context.clone()),
+            chrom_sizes: config.chrom_sizes.clone(),
+            depth_histogram: config.depth_histogram,
+            meth_histogram: config.meth_histogram,
+            meth_histogram_min_depth: config.meth_histogram
+                .map(|_| config.meth_histogram_min_depth),
+            keep_sketch: config.keep_sketch,
+        }
+    }
+}
+
+
+/// Where a report came from and when it was made. The parameters for
+/// calling sites are with the report itself.
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct RunMetadata {
+    pub mlevels_version: String,
+    pub input: InputMetadata,
+    pub filters: FilterMetadata,
+    pub start_time: String,
+    pub end_time: String,
+    pub wall_time_seconds: f64,
+}
+
+
+/// Times a run, to record with its metadata
+pub struct RunTimer {
+    start_time: SystemTime,
+    start: Instant,
+}
+
+
+impl RunTimer {
+    pub fn start() -> RunTimer {
+        RunTimer { start_time: SystemTime::now(), start: Instant::now() }
+    }
+
+    pub fn finish(
+        &self,
+        config: &LevelsConfig,
+        input: InputMetadata,
+    ) -> RunMetadata {
+        let wall_time = self.start.elapsed();
+        RunMetadata {
+            mlevels_version: env!("CARGO_PKG_VERSION").to_string(),
+            input,
+            filters: FilterMetadata::new(config),
+            start_time: humantime::format_rfc3339_seconds(self.start_time)
+                .to_string(),
+            end_time: humantime::format_rfc3339_seconds(self.start_time +
+                                                        wall_time)
+                .to_string(),
+            wall_time_seconds: wall_time.as_secs_f64(),
+        }
+    }
+}
+
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+
+/// SHA-256 of data in memory, as hex
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+
+/// Passes the bytes read through to a SHA-256 that can be taken once
+/// the reader, which may have been moved into a decompressor, is done.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Arc<Mutex<Sha256>>,
+}
+
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader { inner, hasher: Default::default() }
+    }
+
+    /// Something to get the SHA-256 from after reading
+    pub fn digest(&self) -> HashDigest {
+        HashDigest(self.hasher.clone())
+    }
+}
+
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n_read = self.inner.read(buf)?;
+        self.hasher.lock().unwrap().update(&buf[..n_read]);
+        Ok(n_read)
+    }
+}
+
+
+/// The SHA-256 of what a `HashingReader` has read
+pub struct HashDigest(Arc<Mutex<Sha256>>);
+
+
+impl HashDigest {
+    pub fn hex(&self) -> String {
+        hex(&self.0.lock().unwrap().clone().finalize())
+    }
+}
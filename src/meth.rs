@@ -0,0 +1,114 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use serde::{Serialize, Deserialize};
+
+
+// sites below LOW_BAND are in the low band and above HIGH_BAND in the
+// high band; the rest are intermediate
+const LOW_BAND: f64 = 0.2;
+const HIGH_BAND: f64 = 0.8;
+
+
+/// Sites with low (below 0.2), intermediate and high (above 0.8)
+/// methylation levels
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+pub struct MethBands<T> {
+    pub low: T,
+    pub intermediate: T,
+    pub high: T,
+}
+
+
+/// The number of sites with methylation levels in equal bins from 0 to
+/// 1, counting covered sites with at least a minimum depth
+#[derive(Debug,Clone,PartialEq,Serialize,Deserialize)]
+pub struct MethHistogram {
+    pub min_depth: u64,
+    /// Sites in each bin; the last bin includes 1
+    pub counts: Vec<u64>,
+    pub bands: MethBands<u64>,
+
+    // derived values
+    #[serde(default)]
+    pub band_fractions: MethBands<f64>,
+}
+
+
+impl MethHistogram {
+    pub fn new(n_bins: usize, min_depth: u64) -> MethHistogram {
+        MethHistogram {
+            min_depth,
+            counts: vec![0; n_bins],
+            bands: Default::default(),
+            band_fractions: Default::default(),
+        }
+    }
+
+    pub fn add(&mut self, n_reads: u64, meth: f64) {
+        if n_reads < self.min_depth {
+            return;
+        }
+        let n_bins = self.counts.len();
+        let bin = ((meth*n_bins as f64) as usize).min(n_bins - 1);
+        self.counts[bin] += 1;
+        if meth < LOW_BAND {
+            self.bands.low += 1;
+        }
+        else if meth > HIGH_BAND {
+            self.bands.high += 1;
+        }
+        else {
+            self.bands.intermediate += 1;
+        }
+    }
+
+    /// Whether the counts from `other` can be added to these
+    pub fn compatible(&self, other: &MethHistogram) -> bool {
+        self.min_depth == other.min_depth &&
+            self.counts.len() == other.counts.len()
+    }
+
+    /// Add the counts from a compatible histogram. The derived values
+    /// must be set again after.
+    pub fn merge(&mut self, other: &MethHistogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.bands.low += other.bands.low;
+        self.bands.intermediate += other.bands.intermediate;
+        self.bands.high += other.bands.high;
+    }
+
+    pub fn set_derived_values(&mut self) {
+        let total = (self.bands.low + self.bands.intermediate +
+                     self.bands.high) as f64;
+        self.band_fractions = MethBands {
+            low: (self.bands.low as f64)/total,
+            intermediate: (self.bands.intermediate as f64)/total,
+            high: (self.bands.high as f64)/total,
+        };
+    }
+}
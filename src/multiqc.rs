@@ -0,0 +1,233 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::{LevelsReport, MlevelsError};
+
+
+// Each file is one custom content section for MultiQC; the sample
+// names key the data so files from many samples merge into one table
+// or plot.
+#[derive(Serialize)]
+struct CustomContent<H, D> {
+    id: &'static str,
+    section_name: &'static str,
+    description: &'static str,
+    plot_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pconfig: Option<BarPlotConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<H>,
+    data: BTreeMap<String, D>,
+}
+
+
+#[derive(Serialize)]
+struct Header {
+    title: &'static str,
+    description: &'static str,
+    min: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<f64>,
+    suffix: &'static str,
+    format: &'static str,
+    scale: &'static str,
+}
+
+
+#[derive(Serialize)]
+struct GeneralStatsHeaders {
+    cpg_meth: Header,
+    cpg_depth: Header,
+    cpg_covered: Header,
+    chh_meth: Header,
+    conversion: Header,
+}
+
+
+#[derive(Serialize)]
+struct GeneralStats {
+    cpg_meth: f64,
+    cpg_depth: f64,
+    cpg_covered: f64,
+    chh_meth: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conversion: Option<f64>,
+}
+
+
+#[derive(Serialize)]
+struct BarPlotConfig {
+    id: &'static str,
+    title: &'static str,
+    ylab: &'static str,
+    ymax: f64,
+    cpswitch: bool,
+    stacking: Option<&'static str>,
+}
+
+
+fn percent(fraction: f64) -> f64 {
+    100.0*fraction
+}
+
+
+/// The name MultiQC shows for a counts file: the file name without
+/// directories or the usual extensions.
+pub fn sample_name_for(input: &str) -> String {
+    if input == "-" {
+        return "stdin".to_string();
+    }
+    let mut name = Path::new(input).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| input.to_string());
+    for ext in [".gz", ".bgz", ".counts", ".meth", ".txt", ".tsv"] {
+        if let Some(stripped) = name.strip_suffix(ext) {
+            name = stripped.to_string();
+        }
+    }
+    name
+}
+
+
+fn write_yaml<T: Serialize>(
+    path: &str,
+    content: &T,
+) -> Result<(), MlevelsError> {
+    let yaml = serde_yaml::to_string(content)
+        .map_err(|err| MlevelsError::Write(std::io::Error::other(err)))?;
+    let out = File::create(path).map_err(MlevelsError::Write)?;
+    let mut out = BufWriter::new(out);
+    write!(out, "{yaml}").map_err(MlevelsError::Write)?;
+    out.flush().map_err(MlevelsError::Write)
+}
+
+
+/// Write MultiQC custom content for one sample: a general statistics
+/// table and a bar plot of the weighted methylation in each context.
+/// The files are named `{prefix}mlevels_general_stats_mqc.yaml` and
+/// `{prefix}mlevels_contexts_mqc.yaml`.
+pub fn write_multiqc(
+    report: &LevelsReport,
+    sample: &str,
+    prefix: &str,
+) -> Result<(), MlevelsError> {
+    let levels = &report.summary;
+
+    let general_stats = CustomContent {
+        id: "mlevels_general_stats",
+        section_name: "mlevels",
+        description: "Methylation levels from mlevels",
+        plot_type: "generalstats",
+        pconfig: None,
+        headers: Some(GeneralStatsHeaders {
+            cpg_meth: Header {
+                title: "CpG meth",
+                description: "Weighted mean methylation of CpG sites",
+                min: 0.0,
+                max: Some(100.0),
+                suffix: "%",
+                format: "{:,.1f}",
+                scale: "RdYlBu-rev",
+            },
+            cpg_depth: Header {
+                title: "CpG depth",
+                description: "Mean depth of covered CpG sites",
+                min: 0.0,
+                max: None,
+                suffix: "X",
+                format: "{:,.1f}",
+                scale: "Blues",
+            },
+            cpg_covered: Header {
+                title: "CpG covered",
+                description: "Percent of CpG sites covered by reads",
+                min: 0.0,
+                max: Some(100.0),
+                suffix: "%",
+                format: "{:,.1f}",
+                scale: "Greens",
+            },
+            chh_meth: Header {
+                title: "CHH meth",
+                description: "Weighted mean methylation of CHH sites",
+                min: 0.0,
+                max: Some(100.0),
+                suffix: "%",
+                format: "{:,.2f}",
+                scale: "OrRd",
+            },
+            conversion: Header {
+                title: "Conversion",
+                description: "Bisulfite conversion rate from CHH sites",
+                min: 0.0,
+                max: Some(100.0),
+                suffix: "%",
+                format: "{:,.2f}",
+                scale: "RdYlGn",
+            },
+        }),
+        data: BTreeMap::from([(sample.to_string(), GeneralStats {
+            cpg_meth: percent(levels.cpg.mean_meth_weighted),
+            cpg_depth: levels.cpg.mean_depth_covered,
+            cpg_covered: percent(levels.cpg.sites_covered_fraction),
+            chh_meth: percent(levels.chh.mean_meth_weighted),
+            conversion: report.conversion.as_ref()
+                .and_then(|conversion| conversion.chh.as_ref())
+                .map(|chh| percent(chh.rate)),
+        })]),
+    };
+    write_yaml(&format!("{prefix}mlevels_general_stats_mqc.yaml"),
+               &general_stats)?;
+
+    // a mapping keeps the contexts in their usual order
+    let contexts: serde_yaml::Mapping = levels.counters().into_iter()
+        .map(|(context, lc)| (context.into(),
+                              percent(lc.mean_meth_weighted).into()))
+        .collect();
+    let bar_plot: CustomContent<(), _> = CustomContent {
+        id: "mlevels_contexts",
+        section_name: "Methylation by context",
+        description: "Weighted mean methylation in each cytosine context",
+        plot_type: "bargraph",
+        pconfig: Some(BarPlotConfig {
+            id: "mlevels_contexts_plot",
+            title: "mlevels: methylation by context",
+            ylab: "Weighted methylation (%)",
+            ymax: 100.0,
+            cpswitch: false,
+            stacking: None,
+        }),
+        headers: None,
+        data: BTreeMap::from([(sample.to_string(), contexts)]),
+    };
+    write_yaml(&format!("{prefix}mlevels_contexts_mqc.yaml"), &bar_plot)
+}
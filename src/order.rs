@@ -0,0 +1,231 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use msite::MSite;
+use serde::{Serialize, Deserialize};
+
+use crate::MlevelsError;
+
+
+/// What to do with sites that are not in sorted order
+#[derive(Debug,Default,Clone,Copy,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortCheck {
+    /// Stop at the first site out of order
+    #[default]
+    Strict,
+    /// Count the problems and report them with the levels
+    Lenient,
+}
+
+
+impl SortCheck {
+    pub const NAMES: [&'static str; 2] = ["strict", "lenient"];
+}
+
+
+impl FromStr for SortCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SortCheck, String> {
+        match s {
+            "strict" => Ok(SortCheck::Strict),
+            "lenient" => Ok(SortCheck::Lenient),
+            _ => Err(format!("unknown sort check: {s}")),
+        }
+    }
+}
+
+
+/// Problems with the order of the sites in a counts file. Any of these
+/// can make the `cpg_symmetric` counts and the levels for chromosomes
+/// wrong.
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+pub struct SortProblems {
+    /// Sites at a position before that of the previous site
+    pub out_of_order: u64,
+    /// Sites at the same position as the previous site
+    pub duplicates: u64,
+    /// Times sites for a chromosome start again after another
+    pub revisited_chroms: u64,
+}
+
+
+impl SortProblems {
+    pub fn merge(&mut self, other: &SortProblems) {
+        self.out_of_order += other.out_of_order;
+        self.duplicates += other.duplicates;
+        self.revisited_chroms += other.revisited_chroms;
+    }
+}
+
+
+#[derive(Clone,Copy)]
+enum Problem {
+    OutOfOrder,
+    Duplicate,
+    Revisited,
+}
+
+
+impl Problem {
+    fn message(&self) -> &'static str {
+        match self {
+            Problem::OutOfOrder => "site out of order",
+            Problem::Duplicate => "duplicate site",
+            Problem::Revisited => "chromosome not contiguous",
+        }
+    }
+}
+
+
+// Where the sites of a chromosome first appear in a chunk of input
+struct ChromStart {
+    chrom: Vec<u8>,
+    line_number: u64,
+    pos: u64,
+}
+
+
+// Checks each site against the previous one. Chromosomes can be in any
+// order, but all sites for a chromosome must be together.
+#[derive(Default)]
+pub(crate) struct SortChecker {
+    mode: SortCheck,
+    problems: SortProblems,
+    chrom: Option<Vec<u8>>,
+    pos: u64,
+    seen: HashSet<Vec<u8>>,
+    // for joining the checks of consecutive chunks
+    starts: Vec<ChromStart>,
+}
+
+
+impl SortChecker {
+    pub(crate) fn new(mode: SortCheck) -> SortChecker {
+        SortChecker { mode, ..Default::default() }
+    }
+
+    // the problems found, which are only reported in lenient mode
+    pub(crate) fn problems(&self) -> Option<SortProblems> {
+        (self.mode == SortCheck::Lenient).then(|| self.problems.clone())
+    }
+
+    fn found(
+        &mut self,
+        problem: Problem,
+        line_number: u64,
+        chrom: &[u8],
+        pos: u64,
+    ) -> Result<(), MlevelsError> {
+        if self.mode == SortCheck::Strict {
+            return Err(MlevelsError::Unsorted {
+                line_number,
+                site: format!("{}:{pos}", String::from_utf8_lossy(chrom)),
+                problem: problem.message().to_string(),
+            });
+        }
+        match problem {
+            Problem::OutOfOrder => self.problems.out_of_order += 1,
+            Problem::Duplicate => self.problems.duplicates += 1,
+            Problem::Revisited => self.problems.revisited_chroms += 1,
+        }
+        Ok(())
+    }
+
+    // position of the next site compared with the previous one
+    fn check_pos(
+        &mut self,
+        line_number: u64,
+        chrom: &[u8],
+        pos: u64,
+    ) -> Result<(), MlevelsError> {
+        if pos == self.pos {
+            self.found(Problem::Duplicate, line_number, chrom, pos)?;
+        }
+        else if pos < self.pos {
+            self.found(Problem::OutOfOrder, line_number, chrom, pos)?;
+        }
+        Ok(())
+    }
+
+    // check the site on this line of the input against the previous one
+    pub(crate) fn check(
+        &mut self,
+        site: &MSite,
+        line_number: u64,
+    ) -> Result<(), MlevelsError> {
+        if self.chrom.as_ref() == Some(&site.chrom) {
+            self.check_pos(line_number, &site.chrom, site.pos)?;
+        }
+        else {
+            if self.seen.contains(&site.chrom) {
+                self.found(Problem::Revisited, line_number, &site.chrom,
+                           site.pos)?;
+            }
+            else {
+                self.seen.insert(site.chrom.clone());
+                self.starts.push(ChromStart {
+                    chrom: site.chrom.clone(),
+                    line_number,
+                    pos: site.pos,
+                });
+            }
+            self.chrom = Some(site.chrom.clone());
+        }
+        self.pos = site.pos;
+        Ok(())
+    }
+
+    // Continue with the checks for the chunk of input that follows, as
+    // if its sites had been checked here. Line numbers in errors are
+    // those in the next chunk.
+    pub(crate) fn join(
+        &mut self,
+        next: SortChecker,
+    ) -> Result<(), MlevelsError> {
+        // the first site of each chromosome in the next chunk was not
+        // checked against the sites before that chunk
+        for (i, start) in next.starts.iter().enumerate() {
+            if i == 0 && self.chrom.as_ref() == Some(&start.chrom) {
+                self.check_pos(start.line_number, &start.chrom, start.pos)?;
+            }
+            else if self.seen.contains(&start.chrom) {
+                self.found(Problem::Revisited, start.line_number,
+                           &start.chrom, start.pos)?;
+            }
+        }
+        self.problems.merge(&next.problems);
+        self.seen.extend(next.starts.into_iter().map(|start| start.chrom));
+        if next.chrom.is_some() {
+            self.chrom = next.chrom;
+            self.pos = next.pos;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::io::Write;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::table::counter_values;
+use crate::{LevelsReport, MlevelsError, COUNTER_COLUMNS};
+
+
+/// Writes a report in one output format
+pub trait ReportWriter {
+    fn write(
+        &self,
+        report: &LevelsReport,
+        out: &mut dyn Write,
+    ) -> Result<(), MlevelsError>;
+}
+
+
+fn write_error<E>(err: E) -> MlevelsError
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    MlevelsError::Write(std::io::Error::other(err))
+}
+
+
+pub struct YamlWriter;
+
+impl ReportWriter for YamlWriter {
+    fn write(
+        &self,
+        report: &LevelsReport,
+        out: &mut dyn Write,
+    ) -> Result<(), MlevelsError> {
+        let yaml = serde_yaml::to_string(report).map_err(write_error)?;
+        write!(out, "{yaml}").map_err(MlevelsError::Write)
+    }
+}
+
+
+pub struct JsonWriter;
+
+impl ReportWriter for JsonWriter {
+    fn write(
+        &self,
+        report: &LevelsReport,
+        out: &mut dyn Write,
+    ) -> Result<(), MlevelsError> {
+        serde_json::to_writer_pretty(&mut *out, report).map_err(write_error)?;
+        writeln!(out).map_err(MlevelsError::Write)
+    }
+}
+
+
+pub struct TomlWriter;
+
+impl ReportWriter for TomlWriter {
+    fn write(
+        &self,
+        report: &LevelsReport,
+        out: &mut dyn Write,
+    ) -> Result<(), MlevelsError> {
+        let toml = toml::to_string(report).map_err(write_error)?;
+        write!(out, "{toml}").map_err(MlevelsError::Write)
+    }
+}
+
+
+/// One row for each context, with the columns of `COUNTER_COLUMNS`.
+/// Only the genome-wide levels are included.
+pub struct TsvWriter;
+
+impl ReportWriter for TsvWriter {
+    fn write(
+        &self,
+        report: &LevelsReport,
+        out: &mut dyn Write,
+    ) -> Result<(), MlevelsError> {
+        writeln!(out, "context\t{}", COUNTER_COLUMNS.join("\t"))
+            .map_err(MlevelsError::Write)?;
+        for (context, lc) in report.summary.counters() {
+            writeln!(out, "{context}\t{}", counter_values(lc).join("\t"))
+                .map_err(MlevelsError::Write)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// The formats for writing a report
+#[derive(Debug,Default,Clone,Copy,PartialEq,Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Yaml,
+    Json,
+    Tsv,
+    Toml,
+}
+
+
+impl OutputFormat {
+    pub const NAMES: [&'static str; 4] = ["yaml", "json", "tsv", "toml"];
+
+    pub fn writer(&self) -> Box<dyn ReportWriter> {
+        match self {
+            OutputFormat::Yaml => Box::new(YamlWriter),
+            OutputFormat::Json => Box::new(JsonWriter),
+            OutputFormat::Tsv => Box::new(TsvWriter),
+            OutputFormat::Toml => Box::new(TomlWriter),
+        }
+    }
+}
+
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "yaml" => Ok(OutputFormat::Yaml),
+            "json" => Ok(OutputFormat::Json),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "toml" => Ok(OutputFormat::Toml),
+            _ => Err(format!("unknown format: {s}")),
+        }
+    }
+}
@@ -0,0 +1,365 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use msite::MSite;
+
+use crate::{count_slice, parse_site, LevelsConfig, LevelsReport};
+use crate::{MlevelsError, SiteCounter, SiteFilter, SortChecker};
+
+
+// chunks are at least this many bytes, and each thread gets several
+// so that uneven chunks balance out
+const MIN_CHUNK_SIZE: usize = 1 << 20;
+const CHUNKS_PER_THREAD: usize = 4;
+
+
+// Move the target offset to the start of a line, and past the second
+// site of a symmetric CpG pair if the pair would be split, so each
+// chunk can start counting from a fresh state.
+fn chunk_start(data: &[u8], target: usize) -> usize {
+    if target == 0 {
+        return 0;
+    }
+    let offset = match data[target - 1..].iter().position(|&b| b == b'\n') {
+        Some(newline) => target + newline,
+        None => return data.len(),
+    };
+
+    // offset is the start of a line; now check it against the next
+    let mut lines = data[offset..].split_inclusive(|&b| b == b'\n');
+    let (Some(first), Some(second)) = (lines.next(), lines.next()) else {
+        return data.len();
+    };
+    let (mut first_site, mut second_site) = (MSite::new(), MSite::new());
+    let is_pair = parse_site(first, &mut first_site).is_ok() &&
+        parse_site(second, &mut second_site).is_ok() &&
+        first_site.is_cpg() && second_site.is_cpg() &&
+        first_site.is_mate_of(&second_site);
+    if is_pair {
+        offset + first.len() + second.len()
+    }
+    else {
+        offset + first.len()
+    }
+}
+
+
+// Offsets where the chunks start, followed by the end of the data
+fn chunk_offsets(data: &[u8], threads: usize) -> Vec<usize> {
+    let size = data.len();
+    let n_chunks = std::cmp::max(
+        std::cmp::min(threads*CHUNKS_PER_THREAD, size/MIN_CHUNK_SIZE),
+        1,
+    );
+    let mut offsets = vec![0];
+    for i in 1..n_chunks {
+        let start = chunk_start(data, i*size/n_chunks);
+        if start > *offsets.last().unwrap() && start < size {
+            offsets.push(start);
+        }
+    }
+    offsets.push(size);
+    offsets
+}
+
+
+// Line numbers in errors from a chunk are relative to its first line
+fn with_line_offset(err: MlevelsError, offset: u64) -> MlevelsError {
+    match err {
+        MlevelsError::Parse { line_number, line } => {
+            MlevelsError::Parse { line_number: line_number + offset, line }
+        }
+        MlevelsError::UnknownContext { line_number, line } => {
+            MlevelsError::UnknownContext {
+                line_number: line_number + offset,
+                line,
+            }
+        }
+        MlevelsError::Unsorted { line_number, site, problem } => {
+            MlevelsError::Unsorted {
+                line_number: line_number + offset,
+                site,
+                problem,
+            }
+        }
+        err => err,
+    }
+}
+
+
+// The levels for a chunk, the order of its sites to check against the
+// other chunks, and the number of lines in it
+type ChunkResult = Result<(LevelsReport, SortChecker, u64), MlevelsError>;
+
+
+fn report_for_chunk(
+    config: &LevelsConfig,
+    filter: &Option<SiteFilter>,
+    chunk: &[u8],
+) -> ChunkResult {
+    let mut counter = SiteCounter::new(config)?;
+    // the chromosome messages would repeat for every chunk
+    counter.verbose = false;
+    let mut filter = filter.clone();
+    let n_lines = count_slice(&mut counter, &mut filter, chunk)?;
+    let sort = std::mem::take(&mut counter.sort);
+    Ok((counter.finish()?, sort, n_lines))
+}
+
+
+/// Compute levels for uncompressed counts data, e.g., a mapped file,
+/// by splitting it into chunks at line boundaries, never between the
+/// two sites of a symmetric CpG, and counting the chunks on
+/// `config.threads` threads. For sorted input the result is the same
+/// as counting all the lines in order, except that the levels are
+/// summed for each chunk and the sums added in the order of the
+/// chunks, which can change the last digits of the mean levels.
+/// Outputs for regions of interest and bins are not supported.
+pub fn report_from_chunks(
+    config: &LevelsConfig,
+    data: &[u8],
+) -> Result<LevelsReport, MlevelsError> {
+
+    // the BED files are read once for all chunks
+    let filter = SiteFilter::from_config(config)?;
+    let offsets = chunk_offsets(data, config.threads);
+    let n_chunks = offsets.len() - 1;
+    if config.verbose {
+        eprintln!("[processing {n_chunks} chunks on {} threads]",
+                  config.threads);
+    }
+
+    let next_chunk = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<ChunkResult>>> =
+        Mutex::new((0..n_chunks).map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..std::cmp::max(config.threads, 1) {
+            scope.spawn(|| loop {
+                let i = next_chunk.fetch_add(1, Ordering::Relaxed);
+                if i >= n_chunks {
+                    break;
+                }
+                let chunk = &data[offsets[i]..offsets[i + 1]];
+                let result = report_for_chunk(config, &filter, chunk);
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+
+    // combine in order, so errors report the first bad line
+    let mut merged: Option<(LevelsReport, SortChecker)> = None;
+    let mut lines_before = 0;
+    for result in results.into_inner().unwrap() {
+        let (mut report, sort, n_lines) = result
+            .expect("every chunk is processed")
+            .map_err(|err| with_line_offset(err, lines_before))?;
+        if let Some(skipped) = &mut report.skipped_lines {
+            skipped.offset_lines(lines_before);
+        }
+        match &mut merged {
+            None => merged = Some((report, sort)),
+            Some((merged, merged_sort)) => {
+                merged_sort.join(sort)
+                    .map_err(|err| with_line_offset(err, lines_before))?;
+                merged.merge(&report)
+                    .expect("chunks use the same parameters");
+            }
+        }
+        lines_before += n_lines;
+    }
+    let (mut merged, sort) = merged.expect("there is at least one chunk");
+    merged.sort_problems = sort.problems();
+    merged.set_derived_values();
+    Ok(merged)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{report_from_slice, SortCheck};
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    // depths are 0 or powers of two up to 32, the same for both sites
+    // of a CpG pair, so all levels are multiples of 1/64 and sums of
+    // them are exact whatever order they are added in
+    fn random_depth(rng: &mut Rng) -> u64 {
+        match rng.below(8) {
+            0 => 0,
+            _ => 1 << rng.below(6),
+        }
+    }
+
+    fn site_line(chrom: &str, pos: u64, strand: char, context: &str,
+                 n_reads: u64, rng: &mut Rng) -> String {
+        let meth = if n_reads == 0 {
+            0.0
+        }
+        else {
+            rng.below(n_reads + 1) as f64/n_reads as f64
+        };
+        format!("{chrom}\t{pos}\t{strand}\t{context}\t{meth:.6}\t{n_reads}\n")
+    }
+
+    // Several MB of sites, mostly symmetric CpG pairs so chunks are
+    // likely to start between mates, with other contexts, mutated
+    // sites, a revisited chromosome, sites out of order, duplicates
+    // and lines that are not sites.
+    fn synthetic_counts() -> Vec<u8> {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        let mut lines = Vec::new();
+        for (chrom, start) in [("chr1", 0), ("chr2", 0), ("chr1", 10_000_000)] {
+            let mut pos = start;
+            let end = lines.len() + 60_000;
+            while lines.len() < end {
+                pos += 2 + rng.below(20);
+                match rng.below(10) {
+                    0..=5 => {
+                        let context = if rng.below(50) == 0 {
+                            "CpGx"
+                        }
+                        else {
+                            "CpG"
+                        };
+                        let n_reads = random_depth(&mut rng);
+                        lines.push(site_line(chrom, pos, '+', context,
+                                             n_reads, &mut rng));
+                        lines.push(site_line(chrom, pos + 1, '-', context,
+                                             n_reads, &mut rng));
+                        pos += 1;
+                    }
+                    6 | 7 => {
+                        let n_reads = random_depth(&mut rng);
+                        lines.push(site_line(chrom, pos, '-', "CHH", n_reads,
+                                             &mut rng));
+                    }
+                    8 => {
+                        let n_reads = random_depth(&mut rng);
+                        lines.push(site_line(chrom, pos, '+', "CXG", n_reads,
+                                             &mut rng));
+                    }
+                    _ => {
+                        let n_reads = random_depth(&mut rng);
+                        lines.push(site_line(chrom, pos, '+', "CCG", n_reads,
+                                             &mut rng));
+                    }
+                }
+            }
+            lines.push(format!("{chrom}\tbad\tline\n"));
+        }
+        for i in (1000..lines.len()).step_by(7919) {
+            lines.swap(i - 1, i);
+            let duplicate = lines[i].clone();
+            lines.insert(i, duplicate);
+        }
+        lines.concat().into_bytes()
+    }
+
+    // the start of the line after the one holding offset
+    fn next_line_start(data: &[u8], offset: usize) -> usize {
+        offset + 1 + data[offset..].iter().position(|&b| b == b'\n').unwrap()
+    }
+
+    // are the lines starting at these offsets the two sites of a CpG
+    fn are_mates(data: &[u8], first: usize, second: usize) -> bool {
+        let (mut first_site, mut second_site) = (MSite::new(), MSite::new());
+        let end = next_line_start(data, second);
+        parse_site(&data[first..second], &mut first_site).is_ok() &&
+            parse_site(&data[second..end], &mut second_site).is_ok() &&
+            first_site.is_cpg() && second_site.is_cpg() &&
+            first_site.is_mate_of(&second_site)
+    }
+
+    #[test]
+    fn chunk_start_keeps_cpg_mates_together() {
+        let data = b"chr1\t10\t+\tCpG\t0.5\t10\n\
+                     chr1\t11\t-\tCpG\t0.5\t10\n\
+                     chr1\t20\t+\tCHH\t0.1\t10\n";
+        let third_line = 2*data.len()/3;
+        assert_eq!(chunk_start(data, 0), 0);
+        assert_eq!(chunk_start(data, 3), third_line);
+        assert_eq!(chunk_start(data, data.len()/3), third_line);
+        assert_eq!(chunk_start(data, third_line + 1), data.len());
+    }
+
+    #[test]
+    fn chunks_match_serial() {
+        let data = synthetic_counts();
+        let config = LevelsConfig {
+            threads: 4,
+            sort_check: SortCheck::Lenient,
+            skip_bad_lines: true,
+            by_chrom: true,
+            depth_histogram: Some(30),
+            meth_histogram: Some(20),
+            ..Default::default()
+        };
+
+        // some chunks must start by moving past a CpG pair that would
+        // otherwise be split, and none may start between mates
+        let offsets = chunk_offsets(&data, config.threads);
+        let n_chunks = offsets.len() - 1;
+        assert!(n_chunks > 3, "too few chunks: {offsets:?}");
+        let moved_past_pair = (1..n_chunks).filter(|i| {
+            let target = i*data.len()/n_chunks;
+            let next_line = next_line_start(&data, target - 1);
+            are_mates(&data, next_line, next_line_start(&data, next_line))
+        }).count();
+        assert!(moved_past_pair > 0, "no chunk starts near a CpG pair");
+        for &offset in &offsets[1..n_chunks] {
+            let prev_line = data[..offset - 1].iter()
+                .rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+            assert!(!are_mates(&data, prev_line, offset),
+                    "chunk at {offset} splits a CpG pair");
+        }
+
+        let chunked = report_from_chunks(&config, &data).unwrap();
+        let serial = report_from_slice(&config, &data).unwrap();
+        assert_eq!(chunked.lines, serial.lines);
+        assert_eq!(chunked.chromosomes.len(), 2);
+        assert!(chunked.sort_problems.as_ref()
+                .is_some_and(|p| p.out_of_order > 0 && p.duplicates > 0 &&
+                             p.revisited_chroms == 1));
+        assert!(chunked.summary.cpg_symmetric.total_sites > 0);
+
+        let chunked = serde_json::to_value(&chunked).unwrap();
+        let serial = serde_json::to_value(&serial).unwrap();
+        assert_eq!(chunked, serial);
+    }
+}
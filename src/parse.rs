@@ -0,0 +1,198 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use msite::MSite;
+use serde::{Serialize, Deserialize};
+
+
+// line numbers kept as examples for each type of bad line
+const MAX_EXAMPLES: usize = 5;
+
+
+/// Why a line of a counts file is not a site
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ParseError {
+    FieldCount,
+    Position,
+    Strand,
+    Meth,
+    Reads,
+}
+
+
+impl ParseError {
+    /// Name for the type of error when bad lines are counted
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParseError::FieldCount => "wrong_field_count",
+            ParseError::Position => "bad_position",
+            ParseError::Strand => "bad_strand",
+            ParseError::Meth => "bad_meth",
+            ParseError::Reads => "bad_reads",
+        }
+    }
+}
+
+
+/// Lines of one type that were skipped: how many, and the line
+/// numbers of the first few
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+pub struct LineTally {
+    pub count: u64,
+    pub example_lines: Vec<u64>,
+}
+
+
+/// Lines of a counts file skipped because they are not sites of a
+/// known context, by the type of error
+#[derive(Debug,Default,Clone,PartialEq,Serialize,Deserialize)]
+#[serde(transparent)]
+pub struct SkippedLines {
+    pub by_error: BTreeMap<String, LineTally>,
+}
+
+
+impl SkippedLines {
+    pub fn add(&mut self, error: &str, line_number: u64) {
+        let tally = self.by_error.entry(error.to_string()).or_default();
+        tally.count += 1;
+        if tally.example_lines.len() < MAX_EXAMPLES {
+            tally.example_lines.push(line_number);
+        }
+    }
+    /// Add the lines skipped in another part of the input, which come
+    /// after those here
+    pub fn merge(&mut self, other: &SkippedLines) {
+        for (error, other) in &other.by_error {
+            let tally = self.by_error.entry(error.clone()).or_default();
+            tally.count += other.count;
+            tally.example_lines.extend(&other.example_lines);
+            tally.example_lines.truncate(MAX_EXAMPLES);
+        }
+    }
+    // line numbers were counted from the start of a later chunk
+    pub(crate) fn offset_lines(&mut self, offset: u64) {
+        for tally in self.by_error.values_mut() {
+            tally.example_lines.iter_mut().for_each(|line| *line += offset);
+        }
+    }
+}
+
+
+// Parse a field holding a number without copying it
+fn parse_field<T: FromStr>(field: &[u8], err: ParseError)
+                           -> Result<T, ParseError> {
+    std::str::from_utf8(field).ok()
+        .and_then(|field| field.parse().ok())
+        .ok_or(err)
+}
+
+
+/// Parse a line of a counts file into `site`, reusing the buffers that
+/// `site` already holds, so no memory is allocated once the buffers
+/// have grown to fit. The line may end with a newline. The fields are
+/// separated by tabs and are the same as for `MSite::build`, but the
+/// strand must be + or - and the level from 0 to 1; on error `site` is
+/// left partly updated.
+pub fn parse_site(line: &[u8], site: &mut MSite) -> Result<(), ParseError> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let mut fields = line.split(|&b| b == b'\t');
+    let mut next = || fields.next().ok_or(ParseError::FieldCount);
+
+    let chrom = next()?;
+    let pos = next()?;
+    let strand = next()?;
+    let context = next()?;
+    let meth = next()?;
+    let n_reads = next()?;
+    if fields.next().is_some() {
+        return Err(ParseError::FieldCount);
+    }
+
+    site.chrom.clear();
+    site.chrom.extend_from_slice(chrom);
+    site.pos = parse_field(pos, ParseError::Position)?;
+    site.strand = match strand {
+        b"+" | b"-" => strand[0],
+        _ => return Err(ParseError::Strand),
+    };
+    site.context.clear();
+    site.context.extend_from_slice(context);
+    site.meth = parse_field(meth, ParseError::Meth)?;
+    if !(0.0..=1.0).contains(&site.meth) {
+        return Err(ParseError::Meth);
+    }
+    site.n_reads = parse_field(n_reads, ParseError::Reads)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_site_agrees_with_build() {
+        let lines = [
+            "chr1\t10\t+\tCpG\t0.5\t10\n",
+            "chr1\t11\t-\tCHH\t0\t0",
+            "chr1\t10\t+\tCpG\t0.5\t10\textra\n",
+            "chr1\t10\t+\tCpG\t0.5\n",
+            "chr1\t10\t\t+\tCpG\t0.5\t10\n",
+            "chr1\t10\t*\tCpG\t0.5\t10\n",
+            "chr1\t10\t+-\tCpG\t0.5\t10\n",
+            "chr1\tten\t+\tCpG\t0.5\t10\n",
+            "chr1\t10\t+\tCpG\thalf\t10\n",
+            "chr1\t10\t+\tCpG\t0.5\t-1\n",
+        ];
+        let mut site = MSite::new();
+        for line in lines {
+            let parsed = parse_site(line.as_bytes(), &mut site);
+            match MSite::build(line) {
+                Ok(built) => {
+                    assert_eq!(parsed, Ok(()), "{line:?}");
+                    assert_eq!(site.chrom, built.chrom);
+                    assert_eq!(site.pos, built.pos);
+                    assert_eq!(site.strand, built.strand);
+                    assert_eq!(site.context, built.context);
+                    assert_eq!(site.meth, built.meth);
+                    assert_eq!(site.n_reads, built.n_reads);
+                }
+                Err(_) => assert!(parsed.is_err(), "{line:?}"),
+            }
+        }
+        let strand = parse_site(b"chr1\t10\t*\tCpG\t0.5\t10\n", &mut site);
+        assert_eq!(strand, Err(ParseError::Strand));
+        for meth in ["nan", "inf", "-0.1", "1.5"] {
+            let line = format!("chr1\t10\t+\tCpG\t{meth}\t10\n");
+            assert_eq!(parse_site(line.as_bytes(), &mut site),
+                       Err(ParseError::Meth));
+        }
+    }
+}
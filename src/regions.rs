@@ -0,0 +1,327 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufRead;
+use std::sync::Arc;
+
+use crate::{decompressing_reader, LevelsConfig, MlevelsError};
+
+
+/// An interval from a BED file: zero-based, half-open
+#[derive(Debug,Clone,PartialEq)]
+pub struct GenomicRegion {
+    pub chrom: Vec<u8>,
+    pub start: u64,
+    pub end: u64,
+    pub name: Option<String>,
+}
+
+
+impl GenomicRegion {
+    /// Parse a BED line, returning None for headers, comments and
+    /// blank lines.
+    pub fn build(line: &str) -> Result<Option<GenomicRegion>, String> {
+        if line.is_empty() || line.starts_with('#') ||
+            line.starts_with("track") || line.starts_with("browser") {
+            return Ok(None);
+        }
+        let mut fields = line.split('\t');
+        let chrom = fields.next().unwrap();
+        let (start, end) = match (fields.next(), fields.next()) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Err("too few fields".to_string()),
+        };
+        let start: u64 = start.trim().parse()
+            .map_err(|_| format!("bad start: {start}"))?;
+        let end: u64 = end.trim().parse()
+            .map_err(|_| format!("bad end: {end}"))?;
+        if end < start {
+            return Err("end before start".to_string());
+        }
+        let name = fields.next().map(|name| name.to_string());
+        Ok(Some(GenomicRegion {
+            chrom: chrom.as_bytes().to_vec(),
+            start,
+            end,
+            name,
+        }))
+    }
+}
+
+
+/// Reads regions from a BED file, in the order of the file
+pub struct BedReader {
+    path: String,
+    reader: Box<dyn BufRead>,
+    line_number: u64,
+}
+
+
+impl BedReader {
+    pub fn open(path: &str) -> Result<BedReader, MlevelsError> {
+        Ok(BedReader {
+            path: path.to_string(),
+            reader: decompressing_reader(File::open(path)?, 1)?,
+            line_number: 0,
+        })
+    }
+
+    fn error(&self, message: String) -> MlevelsError {
+        MlevelsError::Bed {
+            path: self.path.clone(),
+            line_number: self.line_number,
+            message,
+        }
+    }
+
+    /// The next region, or None at the end of the file
+    pub fn next_region(
+        &mut self,
+    ) -> Result<Option<GenomicRegion>, MlevelsError> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            let line = line.trim_end_matches(['\n', '\r']);
+            let region = GenomicRegion::build(line)
+                .map_err(|message| self.error(message))?;
+            if region.is_some() {
+                return Ok(region);
+            }
+        }
+    }
+}
+
+
+/// The regions of a BED file grouped by chromosome, so they can be
+/// visited in whatever order the chromosomes of the sites are in,
+/// e.g., chr2 before chr10. The file need not be sorted.
+pub struct BedRegions {
+    /// The regions in the order of the file
+    pub regions: Vec<GenomicRegion>,
+    by_chrom: HashMap<Vec<u8>, Vec<usize>>,
+}
+
+
+impl BedRegions {
+    pub fn read(path: &str) -> Result<BedRegions, MlevelsError> {
+        let mut bed = BedReader::open(path)?;
+        let mut regions = Vec::new();
+        while let Some(region) = bed.next_region()? {
+            regions.push(region);
+        }
+        Ok(BedRegions::new(regions))
+    }
+
+    fn new(regions: Vec<GenomicRegion>) -> BedRegions {
+        let mut by_chrom: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+        for (i, region) in regions.iter().enumerate() {
+            by_chrom.entry(region.chrom.clone()).or_default().push(i);
+        }
+        // stable, so regions with the same start stay in file order
+        for indices in by_chrom.values_mut() {
+            indices.sort_by_key(|&i| regions[i].start);
+        }
+        BedRegions { regions, by_chrom }
+    }
+
+    /// Indices of the regions on a chromosome, ordered by start
+    pub fn on_chrom(&self, chrom: &[u8]) -> &[usize] {
+        self.by_chrom.get(chrom).map_or(&[], |indices| indices.as_slice())
+    }
+
+    /// The chromosomes with regions, in no particular order
+    pub fn chroms(&self) -> impl Iterator<Item = &[u8]> {
+        self.by_chrom.keys().map(|chrom| chrom.as_slice())
+    }
+}
+
+
+// The regions of a BED file as disjoint intervals for each
+// chromosome, with overlapping regions merged, and the intervals of
+// the chromosome of the last query. Copies, e.g., for each chunk of
+// the input, share the intervals and only have their own position.
+#[derive(Clone)]
+struct RegionSet {
+    chroms: Arc<HashMap<Vec<u8>, usize>>,
+    intervals: Arc<Vec<Vec<(u64, u64)>>>,
+    chrom: Vec<u8>,
+    current: Option<usize>,
+}
+
+
+impl RegionSet {
+    fn read(path: &str) -> Result<RegionSet, MlevelsError> {
+        Ok(RegionSet::new(&BedRegions::read(path)?))
+    }
+
+    fn new(bed: &BedRegions) -> RegionSet {
+        let mut chroms = HashMap::new();
+        let mut intervals = Vec::new();
+        for chrom in bed.chroms() {
+            let mut merged: Vec<(u64, u64)> = Vec::new();
+            for &i in bed.on_chrom(chrom) {
+                let region = &bed.regions[i];
+                match merged.last_mut() {
+                    Some(last) if region.start <= last.1 => {
+                        last.1 = std::cmp::max(last.1, region.end);
+                    }
+                    _ => merged.push((region.start, region.end)),
+                }
+            }
+            chroms.insert(chrom.to_vec(), intervals.len());
+            intervals.push(merged);
+        }
+        RegionSet {
+            chroms: Arc::new(chroms),
+            intervals: Arc::new(intervals),
+            chrom: Vec::new(),
+            current: None,
+        }
+    }
+
+    // is the position in a region; positions can be queried in any
+    // order, but looking up the chromosome is only needed when it
+    // changes
+    fn contains(&mut self, chrom: &[u8], pos: u64) -> bool {
+        if self.chrom != chrom {
+            self.chrom = chrom.to_vec();
+            self.current = self.chroms.get(chrom).copied();
+        }
+        let Some(current) = self.current else {
+            return false;
+        };
+        let intervals = &self.intervals[current];
+        // the first interval ending after pos
+        let i = intervals.partition_point(|&(_, end)| end <= pos);
+        intervals.get(i).is_some_and(|&(start, _)| start <= pos)
+    }
+}
+
+
+/// Keeps sites inside the regions of one BED file and outside those
+/// of another. The chromosomes of the sites and of the BED files can
+/// be in any order.
+#[derive(Clone)]
+pub struct SiteFilter {
+    include: Option<RegionSet>,
+    exclude: Option<RegionSet>,
+}
+
+
+impl SiteFilter {
+    /// The filter requested in the config, if any
+    pub fn from_config(
+        config: &LevelsConfig,
+    ) -> Result<Option<SiteFilter>, MlevelsError> {
+        if config.regions.is_none() && config.exclude.is_none() {
+            return Ok(None);
+        }
+        let include = config.regions.as_deref().map(RegionSet::read)
+            .transpose()?;
+        let exclude = config.exclude.as_deref().map(RegionSet::read)
+            .transpose()?;
+        Ok(Some(SiteFilter { include, exclude }))
+    }
+
+    /// Should the site at this position be counted
+    pub fn keep(&mut self, chrom: &[u8], pos: u64) -> bool {
+        if let Some(include) = &mut self.include {
+            if !include.contains(chrom, pos) {
+                return false;
+            }
+        }
+        if let Some(exclude) = &mut self.exclude {
+            if exclude.contains(chrom, pos) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regions(lines: &[&str]) -> RegionSet {
+        let regions = lines.iter()
+            .map(|line| GenomicRegion::build(line).unwrap().unwrap())
+            .collect();
+        RegionSet::new(&BedRegions::new(regions))
+    }
+
+    #[test]
+    fn unsorted_bed() {
+        let mut set = regions(&["chr2\t50\t60", "chr1\t30\t40",
+                                "chr2\t10\t20", "chr1\t0\t5"]);
+        for (chrom, pos, inside) in [(&b"chr1"[..], 0, true),
+                                     (b"chr1", 5, false),
+                                     (b"chr2", 15, true),
+                                     (b"chr1", 39, true),
+                                     (b"chr2", 20, false),
+                                     (b"chr2", 59, true),
+                                     (b"chr3", 15, false),
+                                     (b"chr1", 40, false)] {
+            assert_eq!(set.contains(chrom, pos), inside, "{pos}");
+        }
+    }
+
+    #[test]
+    fn overlapping_regions_are_merged() {
+        let set = regions(&["chr1\t10\t20", "chr1\t15\t30",
+                            "chr1\t16\t18", "chr1\t30\t35",
+                            "chr1\t40\t50"]);
+        assert_eq!(set.intervals[set.chroms[&b"chr1"[..]]],
+                   [(10, 35), (40, 50)]);
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let mut filter = SiteFilter {
+            include: Some(regions(&["chr1\t0\t100", "chr2\t0\t100"])),
+            exclude: Some(regions(&["chr1\t40\t60", "chr3\t0\t100"])),
+        };
+        // a copy, as for another chunk, answers the same
+        let mut copy = filter.clone();
+        for (chrom, pos, keep) in [(&b"chr1"[..], 10, true),
+                                   (b"chr1", 50, false),
+                                   (b"chr1", 60, true),
+                                   (b"chr1", 100, false),
+                                   (b"chr2", 50, true),
+                                   (b"chr3", 50, false)] {
+            assert_eq!(filter.keep(chrom, pos), keep, "{pos}");
+            assert_eq!(copy.keep(chrom, pos), keep, "{pos}");
+        }
+        assert!(Arc::ptr_eq(&filter.include.unwrap().intervals,
+                            &copy.include.unwrap().intervals));
+    }
+}
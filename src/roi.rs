@@ -0,0 +1,281 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use msite::MSite;
+
+use crate::{BedRegions, ContextParams, GenomicRegion, LevelsSummary};
+use crate::MlevelsError;
+
+
+// A region that sites may still fall in, with its counters
+struct ActiveRegion {
+    index: usize,
+    levels: LevelsSummary,
+}
+
+
+/// Levels for each region of a BED file, like `dnmtools roi`. The
+/// regions are grouped by chromosome, so the chromosomes of the sites
+/// can be in any order, and along a chromosome only the regions that
+/// overlap the current position are held. A row is written for each
+/// region in the order of the BED file:
+///
+/// chrom start end name sites sites_covered mean_meth
+/// mean_meth_weighted fractional_meth
+pub struct RegionLevels {
+    bed: BedRegions,
+    chrom: Vec<u8>,
+    // regions of the current chromosome by start, and how many of
+    // them have been reached
+    chrom_regions: Vec<usize>,
+    next: usize,
+    // where each chromosome seen so far was left
+    reached: HashMap<Vec<u8>, usize>,
+    active: Vec<ActiveRegion>,
+    // rows of finished regions waiting for those before them
+    rows: Vec<Option<RegionRow>>,
+    written: usize,
+    empty: LevelsSummary,
+    context: usize,
+    out: BufWriter<File>,
+}
+
+
+// The levels written for a region, kept until the rows before it are
+// written
+#[derive(Clone,Copy)]
+struct RegionRow {
+    sites: u64,
+    sites_covered: u64,
+    mean_meth: f64,
+    mean_meth_weighted: f64,
+    fractional_meth: f64,
+}
+
+
+impl RegionRow {
+    fn new(mut levels: LevelsSummary, context: usize) -> RegionRow {
+        levels.set_derived_values();
+        let lc = levels.counters()[context].1;
+        RegionRow {
+            sites: lc.total_sites,
+            sites_covered: lc.sites_covered,
+            mean_meth: lc.mean_meth,
+            mean_meth_weighted: lc.mean_meth_weighted,
+            fractional_meth: lc.fractional_meth,
+        }
+    }
+
+    fn write<W: Write>(
+        &self,
+        out: &mut W,
+        region: &GenomicRegion,
+    ) -> std::io::Result<()> {
+        writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                 String::from_utf8_lossy(&region.chrom),
+                 region.start,
+                 region.end,
+                 region.name.as_deref().unwrap_or("."),
+                 self.sites,
+                 self.sites_covered,
+                 self.mean_meth,
+                 self.mean_meth_weighted,
+                 self.fractional_meth)
+    }
+}
+
+
+impl RegionLevels {
+    /// Summarize the sites of `context` in each region of the BED file
+    /// `input`, using copies of `empty` for the counters, and write the
+    /// result to `output`.
+    pub fn new(
+        input: &str,
+        output: &str,
+        context: &str,
+        empty: &LevelsSummary,
+    ) -> Result<RegionLevels, MlevelsError> {
+        let context = ContextParams::CONTEXTS.iter()
+            .position(|&c| c == context)
+            .expect("context is one of ContextParams::CONTEXTS");
+        let bed = BedRegions::read(input)?;
+        let file = File::create(output).map_err(MlevelsError::Write)?;
+        let mut roi = RegionLevels {
+            rows: vec![None; bed.regions.len()],
+            bed,
+            chrom: Vec::new(),
+            chrom_regions: Vec::new(),
+            next: 0,
+            reached: HashMap::new(),
+            active: Vec::new(),
+            written: 0,
+            empty: empty.clone(),
+            context,
+            out: BufWriter::new(file),
+        };
+        writeln!(roi.out, "chrom\tstart\tend\tname\tsites\tsites_covered\t\
+                           mean_meth\tmean_meth_weighted\tfractional_meth")
+            .map_err(MlevelsError::Write)?;
+        Ok(roi)
+    }
+
+    /// Count the site in each region containing it. A completed
+    /// symmetric CpG is counted in the regions containing its first
+    /// position.
+    pub fn update(
+        &mut self,
+        site: &MSite,
+        symmetric: Option<&MSite>,
+    ) -> Result<(), MlevelsError> {
+        if site.chrom != self.chrom {
+            self.start_chrom(&site.chrom);
+        }
+
+        // regions start being active once the sites reach them
+        while let Some(&index) = self.chrom_regions.get(self.next) {
+            if self.bed.regions[index].start > site.pos {
+                break;
+            }
+            self.active.push(ActiveRegion {
+                index,
+                levels: self.empty.clone(),
+            });
+            self.next += 1;
+        }
+
+        let mut i = 0;
+        while i < self.active.len() {
+            let active = &mut self.active[i];
+            let region = &self.bed.regions[active.index];
+            let contains = |pos: u64| region.start <= pos && pos < region.end;
+            if contains(site.pos) {
+                active.levels.update(site);
+            }
+            if let Some(pair) = symmetric.filter(|pair| contains(pair.pos)) {
+                active.levels.update_symmetric(pair);
+            }
+            if region.end <= site.pos {
+                let done = self.active.swap_remove(i);
+                self.finish_region(done);
+            }
+            else {
+                i += 1;
+            }
+        }
+
+        self.write_rows()
+    }
+
+    // finish the regions of the previous chromosome and move to the
+    // regions of this one, continuing where it was left if the sites
+    // return to it
+    fn start_chrom(&mut self, chrom: &[u8]) {
+        for done in std::mem::take(&mut self.active) {
+            self.finish_region(done);
+        }
+        let prev = std::mem::replace(&mut self.chrom, chrom.to_vec());
+        self.reached.insert(prev, self.next);
+        self.chrom_regions = self.bed.on_chrom(chrom).to_vec();
+        self.next = self.reached.get(chrom).copied().unwrap_or(0);
+    }
+
+    fn finish_region(&mut self, active: ActiveRegion) {
+        self.rows[active.index] =
+            Some(RegionRow::new(active.levels, self.context));
+    }
+
+    // write the rows of finished regions, keeping BED order
+    fn write_rows(&mut self) -> Result<(), MlevelsError> {
+        while let Some(Some(row)) = self.rows.get(self.written) {
+            let region = &self.bed.regions[self.written];
+            row.write(&mut self.out, region).map_err(MlevelsError::Write)?;
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    /// Write the remaining regions, including those after the last
+    /// site or on chromosomes without sites, which have no sites.
+    pub fn finish(mut self) -> Result<(), MlevelsError> {
+        for done in std::mem::take(&mut self.active) {
+            self.finish_region(done);
+        }
+        let no_sites = RegionRow::new(self.empty.clone(), self.context);
+        for (row, region) in self.rows.iter().zip(&self.bed.regions)
+            .skip(self.written) {
+            row.unwrap_or(no_sites).write(&mut self.out, region)
+                .map_err(MlevelsError::Write)?;
+        }
+        self.out.flush().map_err(MlevelsError::Write)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{report_from_slice, LevelsConfig};
+
+    // the chromosomes of the BED file are in another order than those
+    // of the sites, and one has no sites
+    #[test]
+    fn regions_in_bed_order() {
+        let dir = std::env::temp_dir()
+            .join(format!("mlevels-roi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let bed = dir.join("roi.bed");
+        let out = dir.join("roi.tsv");
+        std::fs::write(&bed, "chr3\t0\t100\ta\n\
+                              chr2\t0\t50\tb\n\
+                              chr1\t10\t20\tc\n\
+                              chr2\t40\t60\td\n\
+                              chr1\t0\t5\te\n").unwrap();
+        let counts = b"chr1\t1\t+\tCpG\t1\t2\n\
+                       chr1\t12\t+\tCpG\t0.5\t2\n\
+                       chr1\t15\t+\tCpG\t0\t4\n\
+                       chr2\t45\t+\tCpG\t1\t1\n\
+                       chr2\t55\t-\tCpG\t0\t1\n";
+        let config = LevelsConfig {
+            roi: Some(bed.to_str().unwrap().to_string()),
+            roi_out: Some(out.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        report_from_slice(&config, counts).unwrap();
+        let rows = std::fs::read_to_string(&out).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let rows: Vec<Vec<&str>> = rows.lines().skip(1)
+            .map(|row| row.split('\t').take(7).collect())
+            .collect();
+        assert_eq!(rows, [["chr3", "0", "100", "a", "0", "0", "0"],
+                          ["chr2", "0", "50", "b", "1", "1", "1"],
+                          ["chr1", "10", "20", "c", "2", "2", "0.25"],
+                          ["chr2", "40", "60", "d", "2", "2", "0.5"],
+                          ["chr1", "0", "5", "e", "1", "1", "1"]]);
+    }
+}
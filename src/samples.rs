@@ -0,0 +1,177 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::fs::File;
+use std::io::{prelude::*, BufReader, BufWriter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::table::counter_values;
+use crate::{map_counts, open_counts, report_from_reader, report_from_slice};
+use crate::sample_name_for;
+use crate::{ContextParams, LevelsConfig, LevelsReport, MlevelsError};
+use crate::COUNTER_COLUMNS;
+
+
+/// A sample name and the path to its counts file
+#[derive(Debug,Clone,PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub path: String,
+}
+
+
+impl Sample {
+    /// A sample named for its counts file
+    pub fn from_path(path: &str) -> Sample {
+        Sample { name: sample_name_for(path), path: path.to_string() }
+    }
+}
+
+
+/// Read a sample sheet: each line has a sample name and the path to
+/// its counts file separated by a tab, or only the path. Blank lines
+/// and lines starting with '#' are ignored.
+pub fn read_sample_sheet(path: &str) -> Result<Vec<Sample>, MlevelsError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut samples = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        samples.push(match line.split_once('\t') {
+            Some((name, counts)) => Sample {
+                name: name.to_string(),
+                path: counts.to_string(),
+            },
+            None => Sample::from_path(line),
+        });
+    }
+    Ok(samples)
+}
+
+
+// Levels for one counts file, mapped if it is uncompressed
+fn report_for_counts(
+    config: &LevelsConfig,
+    path: &str,
+) -> Result<LevelsReport, MlevelsError> {
+    let mapped = if config.mmap { map_counts(path)? } else { None };
+    match mapped {
+        Some(data) => report_from_slice(config, &data),
+        None => report_from_reader(config, open_counts(path, config.threads)?),
+    }
+}
+
+
+/// Compute the levels for each sample, processing up to `jobs` files
+/// at a time. The reports are in the order of the samples.
+pub fn levels_for_samples(
+    config: &LevelsConfig,
+    samples: &[Sample],
+    jobs: usize,
+) -> Result<Vec<LevelsReport>, MlevelsError> {
+
+    let next_sample = AtomicUsize::new(0);
+    let reports: Mutex<Vec<Option<Result<LevelsReport, MlevelsError>>>> =
+        Mutex::new((0..samples.len()).map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..std::cmp::max(jobs, 1) {
+            scope.spawn(|| loop {
+                let i = next_sample.fetch_add(1, Ordering::Relaxed);
+                if i >= samples.len() {
+                    break;
+                }
+                let sample = &samples[i];
+                if config.verbose {
+                    eprintln!("[processing sample {}: {}]",
+                              sample.name, sample.path);
+                }
+                let report = report_for_counts(config, &sample.path)
+                    .map_err(|err| MlevelsError::Sample {
+                        sample: sample.name.clone(),
+                        source: Box::new(err),
+                    });
+                reports.lock().unwrap()[i] = Some(report);
+            });
+        }
+    });
+
+    reports.into_inner().unwrap().into_iter()
+        .map(|report| report.expect("every sample is processed"))
+        .collect()
+}
+
+
+/// Write a table with a row for each sample and a column for each
+/// context and statistic, named like `cpg_mean_meth`.
+pub fn write_sample_table<W: Write>(
+    out: &mut W,
+    samples: &[Sample],
+    reports: &[LevelsReport],
+) -> std::io::Result<()> {
+    write!(out, "sample")?;
+    for context in ContextParams::CONTEXTS {
+        for column in COUNTER_COLUMNS {
+            write!(out, "\t{context}_{column}")?;
+        }
+    }
+    writeln!(out)?;
+    for (sample, report) in samples.iter().zip(reports) {
+        write!(out, "{}", sample.name)?;
+        for (_, lc) in report.summary.counters() {
+            write!(out, "\t{}", counter_values(lc).join("\t"))?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+
+/// Compute levels for many samples in parallel and write them as one
+/// table to `output` ("-" for standard output).
+pub fn run_samples(
+    config: &LevelsConfig,
+    samples: &[Sample],
+    jobs: usize,
+    output: &str,
+) -> Result<(), MlevelsError> {
+
+    let reports = levels_for_samples(config, samples, jobs)?;
+
+    let mut out: Box<dyn Write> = if output == "-" {
+        Box::new(std::io::stdout().lock())
+    }
+    else {
+        let file = File::create(output).map_err(MlevelsError::Write)?;
+        Box::new(BufWriter::new(file))
+    };
+    write_sample_table(&mut out, samples, &reports)
+        .and_then(|()| out.flush())
+        .map_err(MlevelsError::Write)
+}
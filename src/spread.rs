@@ -0,0 +1,189 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use serde::{Serialize, Deserialize};
+
+
+use crate::sum::ExactSum;
+
+
+// The variance is from exact sums of the levels and their squares, so
+// it is the same whatever order sites are counted or counters are
+// merged, e.g., across chunks of a file.
+
+/// Sums of levels and of their squares, for the variance
+#[derive(Debug,Default,Clone)]
+pub(crate) struct MethSpread {
+    n: u64,
+    sum: ExactSum,
+    sum_sq: ExactSum,
+}
+
+
+impl MethSpread {
+    /// From a count, mean and sample variance, e.g., read back from
+    /// output; none if the variance is missing for two or more values
+    pub(crate) fn from_summary(
+        n: u64,
+        mean: f64,
+        variance: f64,
+    ) -> Option<MethSpread> {
+        let mut spread = MethSpread { n, ..Default::default() };
+        if n == 0 {
+            return Some(spread);
+        }
+        if n > 1 && !variance.is_finite() {
+            return None;
+        }
+        let n_values = n as f64;
+        spread.sum.add(mean*n_values);
+        spread.sum_sq.add(mean*mean*n_values);
+        if n > 1 {
+            spread.sum_sq.add(variance*(n_values - 1.0));
+        }
+        Some(spread)
+    }
+
+    pub(crate) fn add(&mut self, x: f64) {
+        self.n += 1;
+        self.sum.add(x);
+        self.sum_sq.add(x*x);
+    }
+
+    pub(crate) fn merge(&mut self, other: &MethSpread) {
+        self.n += other.n;
+        self.sum.merge(&other.sum);
+        self.sum_sq.merge(&other.sum_sq);
+    }
+
+    /// The sample variance, which needs at least two values
+    pub(crate) fn variance(&self) -> f64 {
+        if self.n < 2 {
+            return f64::NAN;
+        }
+        let n = self.n as f64;
+        let sum = self.sum.total();
+        let variance = (self.sum_sq.total() - sum*sum/n)/(n - 1.0);
+        variance.max(0.0)
+    }
+}
+
+
+/// Approximate quantiles of levels from 0 to 1, from counts in bins
+/// of width 1/SKETCH_BINS, so quantiles are within half a bin. Counts
+/// are exact so sketches combine without further error, including
+/// when read back from output.
+#[derive(Debug,Default,Clone,Serialize,Deserialize)]
+#[serde(try_from = "SparseCounts", into = "SparseCounts")]
+pub(crate) struct MethSketch {
+    // allocated with the first level, as many counters have none
+    counts: Vec<u64>,
+    n: u64,
+}
+
+
+const SKETCH_BINS: usize = 1000;
+
+
+// A sketch as written to output: only the bins with levels
+#[derive(Serialize,Deserialize)]
+struct SparseCounts {
+    bins: Vec<usize>,
+    counts: Vec<u64>,
+}
+
+
+impl From<MethSketch> for SparseCounts {
+    fn from(sketch: MethSketch) -> SparseCounts {
+        let (bins, counts) = sketch.counts.iter().enumerate()
+            .filter(|(_, &count)| count > 0)
+            .unzip();
+        SparseCounts { bins, counts }
+    }
+}
+
+
+impl TryFrom<SparseCounts> for MethSketch {
+    type Error = String;
+    fn try_from(sparse: SparseCounts) -> Result<MethSketch, String> {
+        if sparse.bins.len() != sparse.counts.len() {
+            return Err("meth_sketch bins and counts differ in length"
+                       .to_string());
+        }
+        let mut sketch = MethSketch::default();
+        for (&bin, &count) in sparse.bins.iter().zip(&sparse.counts) {
+            if bin >= SKETCH_BINS {
+                return Err(format!("meth_sketch bin out of range: {bin}"));
+            }
+            if sketch.counts.is_empty() {
+                sketch.counts = vec![0; SKETCH_BINS];
+            }
+            sketch.counts[bin] += count;
+            sketch.n += count;
+        }
+        Ok(sketch)
+    }
+}
+
+
+impl MethSketch {
+    pub(crate) fn add(&mut self, meth: f64) {
+        if self.counts.is_empty() {
+            self.counts = vec![0; SKETCH_BINS];
+        }
+        let bin = ((meth*SKETCH_BINS as f64) as usize).min(SKETCH_BINS - 1);
+        self.counts[bin] += 1;
+        self.n += 1;
+    }
+
+    pub(crate) fn merge(&mut self, other: &MethSketch) {
+        if other.n == 0 {
+            return;
+        }
+        if self.counts.is_empty() {
+            self.counts = vec![0; SKETCH_BINS];
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.n += other.n;
+    }
+
+    /// The middle of the bin holding the quantile `q`
+    pub(crate) fn quantile(&self, q: f64) -> f64 {
+        if self.n == 0 {
+            return f64::NAN;
+        }
+        let rank = std::cmp::max((q*self.n as f64).ceil() as u64, 1);
+        let mut cumulative = 0;
+        for (bin, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= rank {
+                return (bin as f64 + 0.5)/(SKETCH_BINS as f64);
+            }
+        }
+        1.0
+    }
+}
@@ -0,0 +1,103 @@
+/* MIT License
+ *
+ * Copyright (c) 2023 Andrew Smith
+ *
+ * Permission is hereby granted, free of charge, to any person
+ * obtaining a copy of this software and associated documentation
+ * files (the "Software"), to deal in the Software without
+ * restriction, including without limitation the rights to use, copy,
+ * modify, merge, publish, distribute, sublicense, and/or sell copies
+ * of the Software, and to permit persons to whom the Software is
+ * furnished to do so, subject to the following conditions:
+ *
+ * The above copyright notice and this permission notice shall be
+ * included in all copies or substantial portions of the Software.
+ *
+ * THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+ * EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+ * MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+ * NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS
+ * BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN
+ * ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ * CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+ * SOFTWARE.
+ */
+
+use std::io::{self, Write};
+
+use crate::{ChromLevels, LevelsCounter};
+
+
+/// Columns for a counter in tabular output, in a fixed order
+pub const COUNTER_COLUMNS: [&str; 23] = [
+    "total_sites",
+    "sites_covered",
+    "total_c",
+    "total_t",
+    "max_depth",
+    "mutations",
+    "called_meth",
+    "called_unmeth",
+    "mean_agg",
+    "coverage",
+    "sites_covered_fraction",
+    "mean_depth",
+    "mean_depth_covered",
+    "mean_meth",
+    "mean_meth_weighted",
+    "fractional_meth",
+    "excluded_low_depth",
+    "excluded_high_depth",
+    "meth_variance",
+    "meth_sd",
+    "meth_median",
+    "meth_q25",
+    "meth_q75",
+];
+
+
+// values of a counter in the order of COUNTER_COLUMNS
+pub(crate) fn counter_values(lc: &LevelsCounter) -> [String; 23] {
+    [
+        lc.total_sites.to_string(),
+        lc.sites_covered.to_string(),
+        lc.total_c.to_string(),
+        lc.total_t.to_string(),
+        lc.max_depth.to_string(),
+        lc.mutations.to_string(),
+        lc.called_meth.to_string(),
+        lc.called_unmeth.to_string(),
+        lc.mean_agg.to_string(),
+        lc.coverage.to_string(),
+        lc.sites_covered_fraction.to_string(),
+        lc.mean_depth.to_string(),
+        lc.mean_depth_covered.to_string(),
+        lc.mean_meth.to_string(),
+        lc.mean_meth_weighted.to_string(),
+        lc.fractional_meth.to_string(),
+        lc.excluded_low_depth.to_string(),
+        lc.excluded_high_depth.to_string(),
+        lc.meth_variance.to_string(),
+        lc.meth_sd.to_string(),
+        lc.meth_median.to_string(),
+        lc.meth_q25.to_string(),
+        lc.meth_q75.to_string(),
+    ]
+}
+
+
+/// Write the levels for each chromosome as a table with one row for
+/// each chromosome and context.
+pub fn write_chrom_table<W: Write>(
+    out: &mut W,
+    chromosomes: &[ChromLevels],
+) -> io::Result<()> {
+    writeln!(out, "chrom\tcontext\t{}", COUNTER_COLUMNS.join("\t"))?;
+    for chrom in chromosomes {
+        for (context, lc) in chrom.levels.counters() {
+            writeln!(out, "{}\t{context}\t{}", chrom.chrom,
+                     counter_values(lc).join("\t"))?;
+        }
+    }
+    Ok(())
+}